@@ -0,0 +1,448 @@
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::date::HttpDate;
+use crate::request::RequestLine;
+
+const DEFAULT_KEEP: usize = 5;
+
+/// How each request is written to the access log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// The Common Log Format:
+    /// `host ident user [time] "request line" status bytes`.
+    #[default]
+    Common,
+    /// The Common Log Format followed by the quoted `Referer` and
+    /// `User-Agent`.
+    Combined,
+    /// One JSON object per line, with the duration in milliseconds.
+    Json,
+}
+
+/// One served request, as recorded in the access log.
+#[derive(Debug, Clone)]
+pub struct AccessLogEntry {
+    /// When the first byte of the request arrived, or when the connection
+    /// was turned away.
+    pub time: SystemTime,
+    /// The client's address. `None` on a Unix socket.
+    pub peer_addr: Option<SocketAddr>,
+    /// `None` when the request could not be parsed, or was never read
+    /// because the server turned the connection away with a `503`.
+    pub request_line: Option<RequestLine>,
+    pub status: u16,
+    /// Body bytes sent, not counting headers or chunk framing.
+    pub bytes: u64,
+    /// From the first byte of the request to the response being written, so
+    /// it includes the time taken to upload the request.
+    pub duration: Duration,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+}
+
+impl AccessLogEntry {
+    /// Formats the entry as one log line, without the trailing newline.
+    pub fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Common => self.common(),
+            LogFormat::Combined => format!(
+                "{} \"{}\" \"{}\"",
+                self.common(),
+                quoted(self.referer.as_deref()),
+                quoted(self.user_agent.as_deref())
+            ),
+            LogFormat::Json => self.json(),
+        }
+    }
+
+    fn common(&self) -> String {
+        let host = match self.peer_addr {
+            Some(addr) => addr.ip().to_string(),
+            None => "-".to_string(),
+        };
+        let request = self.request_line.as_ref().map(|line| {
+            format!(
+                "{} {} HTTP/{}",
+                line.method, line.request_target, line.http_version
+            )
+        });
+        // The format writes a body of no bytes as `-`.
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+        format!(
+            "{} - - [{}] \"{}\" {} {}",
+            host,
+            HttpDate::from(self.time).to_common_log(),
+            quoted(request.as_deref()),
+            self.status,
+            bytes
+        )
+    }
+
+    fn json(&self) -> String {
+        let line = self.request_line.as_ref();
+        let peer = self.peer_addr.map(|addr| addr.to_string());
+        format!(
+            "{{\"time\": {}, \"peer\": {}, \"method\": {}, \"target\": {}, \"version\": {}, \
+             \"status\": {}, \"bytes\": {}, \"duration_ms\": {:.3}, \"user_agent\": {}, \
+             \"referer\": {}}}",
+            json_string(Some(&HttpDate::from(self.time).to_rfc3339())),
+            json_string(peer.as_deref()),
+            json_string(line.map(|line| line.method.as_str())),
+            json_string(line.map(|line| line.request_target.as_str())),
+            json_string(line.map(|line| line.http_version.as_str())),
+            self.status,
+            self.bytes,
+            self.duration.as_secs_f64() * 1000.0,
+            json_string(self.user_agent.as_deref()),
+            json_string(self.referer.as_deref())
+        )
+    }
+}
+
+/// A quoted field of the Common Log Format, with quotes, backslashes and
+/// control characters escaped so a client cannot forge a log line. Missing
+/// values are written as `-`.
+fn quoted(value: Option<&str>) -> String {
+    let Some(value) = value else {
+        return "-".to_string();
+    };
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_ascii_control() => {
+                let _ = write!(escaped, "\\x{:02x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn json_string(value: Option<&str>) -> String {
+    let Some(value) = value else {
+        return "null".to_string();
+    };
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+enum Sink {
+    Stdout,
+    File(LogFile),
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl LogFile {
+    fn open(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, file, size })
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        *self = Self::open(self.path.clone())?;
+        Ok(())
+    }
+
+    // Shifts `access.log.1` to `access.log.2` and so on, dropping the
+    // oldest, then starts a fresh `access.log`.
+    fn rotate(&mut self, keep: usize) -> io::Result<()> {
+        if keep == 0 {
+            fs::remove_file(&self.path).or_else(ignore_missing)?;
+        }
+        for n in (1..keep).rev() {
+            fs::rename(rotated(&self.path, n), rotated(&self.path, n + 1))
+                .or_else(ignore_missing)?;
+        }
+        if keep > 0 {
+            fs::rename(&self.path, rotated(&self.path, 1)).or_else(ignore_missing)?;
+        }
+        self.reopen()
+    }
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn ignore_missing(e: io::Error) -> io::Result<()> {
+    if e.kind() == io::ErrorKind::NotFound {
+        Ok(())
+    } else {
+        Err(e)
+    }
+}
+
+/// Where the server records each request it answers, in one of the
+/// [`LogFormat`]s.
+///
+/// A log file can be rotated by size, keeping a number of older files as
+/// `<path>.1`, `<path>.2` and so on, or reopened on request so an external
+/// tool such as logrotate can move it away. Failures to write are reported
+/// on stderr and never fail the request.
+#[derive(Clone)]
+pub struct AccessLog {
+    format: LogFormat,
+    max_size: Option<u64>,
+    keep: usize,
+    sink: Arc<Mutex<Sink>>,
+    reopen: Arc<AtomicBool>,
+}
+
+impl std::fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sink = match &*self.sink.lock().unwrap_or_else(|e| e.into_inner()) {
+            Sink::Stdout => "stdout".to_string(),
+            Sink::File(file) => file.path.display().to_string(),
+        };
+        f.debug_struct("AccessLog")
+            .field("format", &self.format)
+            .field("sink", &sink)
+            .field("max_size", &self.max_size)
+            .field("keep", &self.keep)
+            .finish()
+    }
+}
+
+impl AccessLog {
+    pub fn stdout() -> Self {
+        Self::with_sink(Sink::Stdout)
+    }
+
+    /// Appends to the file at `path`, creating it if needed.
+    pub fn file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::with_sink(Sink::File(LogFile::open(
+            path.as_ref().to_path_buf(),
+        )?)))
+    }
+
+    fn with_sink(sink: Sink) -> Self {
+        Self {
+            format: LogFormat::default(),
+            max_size: None,
+            keep: DEFAULT_KEEP,
+            sink: Arc::new(Mutex::new(sink)),
+            reopen: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Rotates the log file before it would grow past `max_size` bytes.
+    /// `None`, the default, never rotates. Has no effect on stdout.
+    pub fn max_size(mut self, max_size: Option<u64>) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// How many rotated files to keep. Defaults to 5.
+    pub fn keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// Reopens the log file before the next entry is written, once it has
+    /// been moved away. Safe to call from anywhere.
+    pub fn reopen(&self) {
+        self.reopen.store(true, Ordering::SeqCst);
+    }
+
+    /// Reopens the log file whenever the process receives `SIGHUP`, as
+    /// logrotate and similar tools expect. The signal no longer terminates
+    /// the process.
+    #[cfg(unix)]
+    pub fn reopen_on_sighup(self) -> io::Result<Self> {
+        signal_hook::flag::register(libc::SIGHUP, Arc::clone(&self.reopen))?;
+        Ok(self)
+    }
+
+    /// Writes `entry` as one line.
+    pub fn log(&self, entry: &AccessLogEntry) {
+        let mut line = entry.format(self.format);
+        line.push('\n');
+        if let Err(e) = self.write_line(line.as_bytes()) {
+            eprintln!("Error writing access log: {}", e);
+        }
+    }
+
+    fn write_line(&self, line: &[u8]) -> io::Result<()> {
+        let mut sink = self.sink.lock().unwrap_or_else(|e| e.into_inner());
+        let file = match &mut *sink {
+            Sink::Stdout => return io::stdout().lock().write_all(line),
+            Sink::File(file) => file,
+        };
+        if self.reopen.swap(false, Ordering::SeqCst) {
+            file.reopen()?;
+        }
+        let len = line.len() as u64;
+        if let Some(max_size) = self.max_size
+            && file.size > 0
+            && file.size + len > max_size
+        {
+            file.rotate(self.keep)?;
+        }
+        file.file.write_all(line)?;
+        file.size += len;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn entry() -> AccessLogEntry {
+        AccessLogEntry {
+            time: UNIX_EPOCH + Duration::from_secs(784_111_777),
+            peer_addr: Some("192.0.2.7:51000".parse().unwrap()),
+            request_line: Some(RequestLine {
+                method: "GET".to_string(),
+                request_target: "/index.html?q=1".to_string(),
+                http_version: "1.1".to_string(),
+            }),
+            status: 200,
+            bytes: 2326,
+            duration: Duration::from_micros(1500),
+            user_agent: Some("curl/8.5.0".to_string()),
+            referer: None,
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("http-from-tcp-{}-{}.log", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_common_and_combined_formats() {
+        assert_eq!(
+            entry().format(LogFormat::Common),
+            "192.0.2.7 - - [06/Nov/1994:08:49:37 +0000] \"GET /index.html?q=1 HTTP/1.1\" 200 2326"
+        );
+        assert_eq!(
+            entry().format(LogFormat::Combined),
+            "192.0.2.7 - - [06/Nov/1994:08:49:37 +0000] \"GET /index.html?q=1 HTTP/1.1\" 200 2326 \
+             \"-\" \"curl/8.5.0\""
+        );
+    }
+
+    #[test]
+    fn test_common_format_without_request_or_body() {
+        let entry = AccessLogEntry {
+            peer_addr: None,
+            request_line: None,
+            status: 400,
+            bytes: 0,
+            ..entry()
+        };
+        assert_eq!(
+            entry.format(LogFormat::Common),
+            "- - - [06/Nov/1994:08:49:37 +0000] \"-\" 400 -"
+        );
+    }
+
+    #[test]
+    fn test_quoted_fields_are_escaped() {
+        let entry = AccessLogEntry {
+            user_agent: Some("evil\" 200 0\n\\".to_string()),
+            ..entry()
+        };
+        assert!(
+            entry
+                .format(LogFormat::Combined)
+                .ends_with("\"-\" \"evil\\\" 200 0\\x0a\\\\\"")
+        );
+    }
+
+    #[test]
+    fn test_json_format() {
+        let entry = AccessLogEntry {
+            referer: Some("https://example.com/\"a\"".to_string()),
+            ..entry()
+        };
+        assert_eq!(
+            entry.format(LogFormat::Json),
+            "{\"time\": \"1994-11-06T08:49:37Z\", \"peer\": \"192.0.2.7:51000\", \
+             \"method\": \"GET\", \"target\": \"/index.html?q=1\", \"version\": \"1.1\", \
+             \"status\": 200, \"bytes\": 2326, \"duration_ms\": 1.500, \
+             \"user_agent\": \"curl/8.5.0\", \"referer\": \"https://example.com/\\\"a\\\"\"}"
+        );
+    }
+
+    #[test]
+    fn test_rotates_by_size() {
+        let path = temp_path("rotate");
+        let line_len = entry().format(LogFormat::Common).len() as u64 + 1;
+        let log = AccessLog::file(&path)
+            .unwrap()
+            .max_size(Some(line_len * 2))
+            .keep(2);
+        for _ in 0..7 {
+            log.log(&entry());
+        }
+
+        let lines = |path: &Path| fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&rotated(&path, 1)), 2);
+        assert_eq!(lines(&rotated(&path, 2)), 2);
+        assert!(!rotated(&path, 3).exists());
+        for file in [path.clone(), rotated(&path, 1), rotated(&path, 2)] {
+            fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_reopen_after_move() {
+        let path = temp_path("reopen");
+        let moved = rotated(&path, 1);
+        let log = AccessLog::file(&path).unwrap();
+        log.log(&entry());
+        fs::rename(&path, &moved).unwrap();
+
+        // Until it is reopened the log follows the moved file.
+        log.log(&entry());
+        log.reopen();
+        log.log(&entry());
+        assert_eq!(fs::read_to_string(&moved).unwrap().lines().count(), 2);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        fs::remove_file(path).unwrap();
+        fs::remove_file(moved).unwrap();
+    }
+}
@@ -0,0 +1,330 @@
+use std::fmt;
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::{BorrowedFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use socket2::{SockRef, Type};
+
+/// The first file descriptor a service manager passes, after stdin, stdout
+/// and stderr.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+// Each inherited descriptor may only be owned once.
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// A listening socket inherited from the process that started this one.
+#[derive(Debug)]
+pub enum InheritedListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// A listener passed in by socket activation, with the name the service
+/// manager gave it, if any.
+#[derive(Debug)]
+pub struct ActivatedListener {
+    pub name: Option<String>,
+    pub listener: InheritedListener,
+}
+
+/// Why the listeners passed in by socket activation could not be used.
+#[derive(Debug)]
+pub enum ActivationError {
+    /// An activation environment variable does not hold what it should.
+    InvalidVar { var: &'static str, value: String },
+    /// `LISTEN_FDNAMES` names a different number of sockets than
+    /// `LISTEN_FDS` passes.
+    NameCount { fds: usize, names: usize },
+    /// A passed descriptor could not be inspected, usually because it is not
+    /// open or not a socket.
+    Socket { fd: RawFd, source: io::Error },
+    /// A passed descriptor is a socket the server cannot listen on, such as
+    /// a datagram socket.
+    Unsupported { fd: RawFd },
+    /// More sockets were passed than the caller can serve on.
+    TooManyListeners { count: usize },
+}
+
+impl fmt::Display for ActivationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActivationError::InvalidVar { var, value } => {
+                write!(f, "Invalid {}: {:?}", var, value)
+            }
+            ActivationError::NameCount { fds, names } => {
+                write!(f, "LISTEN_FDNAMES has {} names for {} sockets", names, fds)
+            }
+            ActivationError::Socket { fd, source } => {
+                write!(f, "Inherited descriptor {} is unusable: {}", fd, source)
+            }
+            ActivationError::Unsupported { fd } => {
+                write!(f, "Inherited descriptor {} is not a stream socket", fd)
+            }
+            ActivationError::TooManyListeners { count } => {
+                write!(f, "Expected one inherited listener, got {}", count)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ActivationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ActivationError::Socket { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Takes the listeners passed in by a service manager such as systemd,
+/// described by the `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES`
+/// environment variables. They are returned in the order they were passed.
+///
+/// Once the listeners are taken the variables are removed, so processes the
+/// server starts do not mistake them for their own. As with
+/// [`std::env::remove_var`], call this while starting up, before other
+/// threads may be reading the environment.
+///
+/// Returns no listeners when the variables are unset or were meant for
+/// another process, and on every call after the first that succeeded, since
+/// each descriptor can only have one owner.
+pub fn listen_fds() -> Result<Vec<ActivatedListener>, ActivationError> {
+    let listeners = take(passed_from_env()?)?;
+    if !listeners.is_empty() {
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            // SAFETY: the caller is starting up and not reading the
+            // environment from other threads, as documented above.
+            unsafe { std::env::remove_var(name) };
+        }
+    }
+    Ok(listeners)
+}
+
+/// How many listeners [`listen_fds`] would return, without taking them.
+pub(crate) fn passed_count() -> Result<usize, ActivationError> {
+    if TAKEN.load(Ordering::SeqCst) {
+        return Ok(0);
+    }
+    Ok(passed_from_env()?.len())
+}
+
+/// The descriptors the environment says were passed to this process.
+fn passed_from_env() -> Result<Vec<(RawFd, Option<String>)>, ActivationError> {
+    let var = |name| std::env::var(name).ok();
+    parse_env(
+        var("LISTEN_PID").as_deref(),
+        var("LISTEN_FDS").as_deref(),
+        var("LISTEN_FDNAMES").as_deref(),
+        std::process::id(),
+    )
+}
+
+/// Takes ownership of the `passed` descriptors, unless they have been taken
+/// already.
+fn take(passed: Vec<(RawFd, Option<String>)>) -> Result<Vec<ActivatedListener>, ActivationError> {
+    if passed.is_empty() || TAKEN.load(Ordering::SeqCst) {
+        return Ok(Vec::new());
+    }
+
+    // Every descriptor is checked before any is taken over, so an error
+    // leaves them all unowned and a later call may try again.
+    let mut kinds = Vec::with_capacity(passed.len());
+    for (fd, _) in &passed {
+        kinds.push(socket_kind(*fd)?);
+        set_cloexec(*fd).map_err(|source| ActivationError::Socket { fd: *fd, source })?;
+    }
+    if TAKEN
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        return Ok(Vec::new());
+    }
+
+    let listeners = passed.into_iter().zip(kinds).map(|((fd, name), unix)| {
+        // SAFETY: the service manager handed this descriptor to this
+        // process, it is an open socket, and `TAKEN` ensures it is only
+        // wrapped once.
+        let listener = unsafe {
+            if unix {
+                InheritedListener::Unix(UnixListener::from_raw_fd(fd))
+            } else {
+                InheritedListener::Tcp(TcpListener::from_raw_fd(fd))
+            }
+        };
+        ActivatedListener { name, listener }
+    });
+    Ok(listeners.collect())
+}
+
+/// The descriptors and names described by the activation variables, if they
+/// were meant for the process `own_pid`.
+fn parse_env(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+    own_pid: u32,
+) -> Result<Vec<(RawFd, Option<String>)>, ActivationError> {
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(Vec::new());
+    };
+    let invalid = |var, value: &str| ActivationError::InvalidVar {
+        var,
+        value: value.to_string(),
+    };
+    let pid: u32 = pid.parse().map_err(|_| invalid("LISTEN_PID", pid))?;
+    if pid != own_pid {
+        return Ok(Vec::new());
+    }
+    let count: usize = fds.parse().map_err(|_| invalid("LISTEN_FDS", fds))?;
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+    if count > (RawFd::MAX - LISTEN_FDS_START) as usize {
+        return Err(invalid("LISTEN_FDS", fds));
+    }
+
+    let names: Vec<Option<String>> = match names {
+        Some(names) => names
+            .split(':')
+            .map(|name| Some(name.to_string()))
+            .collect(),
+        None => vec![None; count],
+    };
+    if names.len() != count {
+        return Err(ActivationError::NameCount {
+            fds: count,
+            names: names.len(),
+        });
+    }
+    Ok((LISTEN_FDS_START..).zip(names).collect())
+}
+
+/// Whether `fd` is a Unix socket, as opposed to a TCP one. Anything other
+/// than a stream socket is an error.
+fn socket_kind(fd: RawFd) -> Result<bool, ActivationError> {
+    let socket_error = |source| ActivationError::Socket { fd, source };
+    // SAFETY: the descriptor is only borrowed for the duration of this call;
+    // if it is not open the calls below fail with EBADF.
+    let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+    let socket = SockRef::from(&borrowed);
+    if socket.r#type().map_err(socket_error)? != Type::STREAM {
+        return Err(ActivationError::Unsupported { fd });
+    }
+    let addr = socket.local_addr().map_err(socket_error)?;
+    if addr.is_unix() {
+        Ok(true)
+    } else if addr.as_socket().is_some() {
+        Ok(false)
+    } else {
+        Err(ActivationError::Unsupported { fd })
+    }
+}
+
+// Inherited descriptors arrive without close-on-exec, so without this every
+// process the server starts would hold the listener open too.
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    // SAFETY: F_GETFD and F_SETFD only read and set the descriptor's flags.
+    let result = unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 {
+            flags
+        } else {
+            libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC)
+        }
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::{AsRawFd, IntoRawFd};
+
+    #[test]
+    fn test_parse_env_numbers_fds_from_three() {
+        let passed = parse_env(Some("42"), Some("2"), Some("http:admin"), 42).unwrap();
+        assert_eq!(
+            passed,
+            vec![
+                (3, Some("http".to_string())),
+                (4, Some("admin".to_string()))
+            ]
+        );
+        let passed = parse_env(Some("42"), Some("1"), None, 42).unwrap();
+        assert_eq!(passed, vec![(3, None)]);
+    }
+
+    #[test]
+    fn test_parse_env_ignores_other_processes() {
+        assert!(
+            parse_env(Some("41"), Some("1"), None, 42)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(parse_env(None, Some("1"), None, 42).unwrap().is_empty());
+        assert!(parse_env(Some("42"), None, None, 42).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_env_rejects_bad_values() {
+        assert!(matches!(
+            parse_env(Some("42"), Some("many"), None, 42),
+            Err(ActivationError::InvalidVar {
+                var: "LISTEN_FDS",
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse_env(Some("me"), Some("1"), None, 42),
+            Err(ActivationError::InvalidVar {
+                var: "LISTEN_PID",
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse_env(Some("42"), Some("2"), Some("http"), 42),
+            Err(ActivationError::NameCount { fds: 2, names: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_descriptors_are_taken_only_on_success() {
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(matches!(
+            take(vec![(udp.as_raw_fd(), None)]),
+            Err(ActivationError::Unsupported { .. })
+        ));
+        assert!(!TAKEN.load(Ordering::SeqCst));
+
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let taken = take(vec![(tcp.into_raw_fd(), Some("http".to_string()))]).unwrap();
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].name.as_deref(), Some("http"));
+        match &taken[0].listener {
+            InheritedListener::Tcp(listener) => assert_eq!(listener.local_addr().unwrap(), addr),
+            other => panic!("unexpected listener {:?}", other),
+        }
+        // Taken once, never again.
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(take(vec![(tcp.as_raw_fd(), None)]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_socket_kind() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(!socket_kind(tcp.as_raw_fd()).unwrap());
+        let (unix, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        assert!(socket_kind(unix.as_raw_fd()).unwrap());
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(matches!(
+            socket_kind(udp.as_raw_fd()),
+            Err(ActivationError::Unsupported { .. })
+        ));
+    }
+}
@@ -0,0 +1,126 @@
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+
+use crate::writer::{ResponseWriter, WriterError};
+
+// Streamed bodies are copied to the connection at most this much at a time.
+const STREAM_CHUNK_SIZE: usize = 8 * 1024;
+
+/// A response body: either bytes already in memory or a source that is read
+/// while the response is being sent.
+pub enum Body {
+    Bytes(Vec<u8>),
+    Reader {
+        reader: Box<dyn Read + Send>,
+        /// The exact number of bytes the reader will produce, when known.
+        len: Option<u64>,
+    },
+}
+
+impl Body {
+    pub fn empty() -> Self {
+        Body::Bytes(Vec::new())
+    }
+
+    /// A body read from `reader`. With a known `len` it is sent with
+    /// `content-length`, otherwise with chunked transfer coding.
+    pub fn from_reader(reader: impl Read + Send + 'static, len: Option<u64>) -> Self {
+        Body::Reader {
+            reader: Box::new(reader),
+            len,
+        }
+    }
+
+    /// The body's length, if known before it is sent.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Reader { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// Copies a streamed body to `writer`, bounded by `len` when given. A
+    /// failing source is logged and returned as an error without ending the
+    /// body, so the client sees a truncated response rather than a complete
+    /// one.
+    pub(crate) fn copy_reader<W: Write>(
+        reader: &mut dyn Read,
+        len: Option<u64>,
+        writer: &mut ResponseWriter<W>,
+    ) -> Result<u64, WriterError> {
+        let mut buffer = vec![0u8; STREAM_CHUNK_SIZE];
+        let mut written = 0u64;
+
+        loop {
+            let want = match len {
+                Some(len) if written >= len => break,
+                Some(len) => STREAM_CHUNK_SIZE.min((len - written) as usize),
+                None => STREAM_CHUNK_SIZE,
+            };
+            let n = match reader.read(&mut buffer[..want]) {
+                Ok(0) if len.is_some() => {
+                    eprintln!(
+                        "Error reading response body: source ended after {} of {} bytes",
+                        written,
+                        len.unwrap_or_default()
+                    );
+                    return Err(WriterError::Io(ErrorKind::UnexpectedEof.into()));
+                }
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    eprintln!("Error reading response body: {}", e);
+                    return Err(WriterError::Io(e));
+                }
+            };
+            writer.write_body(&buffer[..n])?;
+            written += n as u64;
+        }
+
+        Ok(written)
+    }
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(&bytes.len()).finish(),
+            Body::Reader { len, .. } => f.debug_struct("Reader").field("len", len).finish(),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Self {
+        Body::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<String> for Body {
+    fn from(s: String) -> Self {
+        Body::Bytes(s.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(s: &str) -> Self {
+        Body::Bytes(s.as_bytes().to_vec())
+    }
+}
@@ -0,0 +1,538 @@
+use std::io::{Read, Write};
+
+use flate2::Compression as Level;
+use flate2::read::{GzDecoder, GzEncoder, ZlibDecoder, ZlibEncoder};
+use flate2::write::{GzEncoder as GzWriter, ZlibEncoder as ZlibWriter};
+
+use crate::body::Body;
+use crate::request::Request;
+use crate::response::{Response, StatusCode};
+
+const DEFAULT_MIN_SIZE: usize = 1024;
+const DEFAULT_CONTENT_TYPES: [&str; 6] = [
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/problem+json",
+    "image/svg+xml",
+];
+
+/// A content coding this server can apply. `Deflate` is the zlib format, as
+/// the `deflate` coding is defined in RFC 9110.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// Picks the preferred supported coding from an `Accept-Encoding` value,
+/// or `None` if the body should be left as it is. Codings with `q=0` or a
+/// malformed q-value, such as one above 1, are refused and `*` stands for
+/// any coding not listed explicitly; gzip wins ties.
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut gzip = None;
+    let mut deflate = None;
+    let mut any = None;
+
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let mut q = 1.0f32;
+        for param in params {
+            if let Some((key, value)) = param.split_once('=')
+                && key.trim().eq_ignore_ascii_case("q")
+            {
+                q = parse_qvalue(value.trim()).unwrap_or(0.0);
+            }
+        }
+        match coding.as_str() {
+            "gzip" | "x-gzip" => gzip = Some(q),
+            "deflate" => deflate = Some(q),
+            "*" => any = Some(q),
+            _ => {}
+        }
+    }
+
+    let gzip = gzip.or(any).unwrap_or(0.0);
+    let deflate = deflate.or(any).unwrap_or(0.0);
+    if gzip <= 0.0 && deflate <= 0.0 {
+        return None;
+    }
+    if gzip >= deflate {
+        Some(Encoding::Gzip)
+    } else {
+        Some(Encoding::Deflate)
+    }
+}
+
+/// A weight as RFC 9110 section 12.4.2 defines it: `0` to `1` with at most
+/// three decimals.
+fn parse_qvalue(value: &str) -> Option<f32> {
+    let (whole, decimals) = value.split_once('.').unwrap_or((value, ""));
+    let valid = match whole {
+        "0" => decimals.bytes().all(|b| b.is_ascii_digit()),
+        "1" => decimals.bytes().all(|b| b == b'0'),
+        _ => false,
+    };
+    if !valid || decimals.len() > 3 {
+        return None;
+    }
+    value.parse().ok()
+}
+
+/// Opt-in response compression. Responses are compressed when the client
+/// accepts gzip or deflate, the content type is on the list and the body is
+/// at least `min_size` bytes (streamed bodies of unknown size always
+/// qualify). Bodies that already have a `content-encoding`, partial content
+/// and responses marked `cache-control: no-transform` are left alone.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    min_size: usize,
+    content_types: Vec<String>,
+    level: u32,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Self {
+            min_size: DEFAULT_MIN_SIZE,
+            content_types: DEFAULT_CONTENT_TYPES
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+            level: Level::default().level(),
+        }
+    }
+
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Replaces the list of compressible content types. An entry ending in
+    /// `/*` matches every subtype.
+    pub fn content_types(mut self, content_types: &[&str]) -> Self {
+        self.content_types = content_types.iter().map(|t| t.to_lowercase()).collect();
+        self
+    }
+
+    /// The compression level, from 0 (none) to 9 (best).
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = level.min(9);
+        self
+    }
+
+    /// Compresses `response` for `request` if it is eligible, adjusting
+    /// `content-encoding`, `vary`, `content-length` and the entity tag.
+    pub fn compress(&self, request: &Request, mut response: Response) -> Response {
+        if !self.is_eligible(&response) {
+            return response;
+        }
+        // The representation now depends on Accept-Encoding even when this
+        // particular client gets it uncompressed.
+        add_vary(&mut response, "accept-encoding");
+
+        let too_small = response
+            .body
+            .len()
+            .is_some_and(|len| len < self.min_size as u64);
+        let encoding = request.headers.get("accept-encoding").and_then(negotiate);
+        let encoding = match encoding {
+            Some(encoding) if !too_small => encoding,
+            _ => return response,
+        };
+
+        let level = Level::new(self.level);
+        response.body = match std::mem::take(&mut response.body) {
+            Body::Bytes(bytes) => match compress_bytes(&bytes, encoding, level) {
+                Ok(compressed) => Body::Bytes(compressed),
+                Err(e) => {
+                    eprintln!("Error compressing response body: {}", e);
+                    response.body = Body::Bytes(bytes);
+                    return response;
+                }
+            },
+            Body::Reader { reader, .. } => match encoding {
+                Encoding::Gzip => Body::from_reader(GzEncoder::new(reader, level), None),
+                Encoding::Deflate => Body::from_reader(ZlibEncoder::new(reader, level), None),
+            },
+        };
+
+        response.headers.set("content-encoding", encoding.as_str());
+        response.headers.map.remove("content-length");
+        // A strong tag would promise byte equality with the uncompressed
+        // representation, so it is weakened.
+        if let Some(etag) = response.headers.get("etag")
+            && !etag.starts_with("W/")
+        {
+            let weak = format!("W/{}", etag);
+            response.headers.set("etag", &weak);
+        }
+        response
+    }
+
+    fn is_eligible(&self, response: &Response) -> bool {
+        let headers = &response.headers;
+        if !response.status.allows_body()
+            || response.status == StatusCode::PartialContent
+            || headers.get("content-range").is_some()
+            || headers.get("content-encoding").is_some()
+            || headers
+                .get("cache-control")
+                .is_some_and(|c| c.to_ascii_lowercase().contains("no-transform"))
+        {
+            return false;
+        }
+
+        let content_type = match headers.get("content-type") {
+            Some(content_type) => content_type,
+            None => return false,
+        };
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+        self.content_types
+            .iter()
+            .any(|pattern| match pattern.strip_suffix("/*") {
+                Some(kind) => mime.split('/').next() == Some(kind),
+                None => *pattern == mime,
+            })
+    }
+}
+
+fn compress_bytes(bytes: &[u8], encoding: Encoding, level: Level) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzWriter::new(Vec::new(), level);
+            encoder.write_all(bytes)?;
+            encoder.finish()
+        }
+        Encoding::Deflate => {
+            let mut encoder = ZlibWriter::new(Vec::new(), level);
+            encoder.write_all(bytes)?;
+            encoder.finish()
+        }
+    }
+}
+
+fn add_vary(response: &mut Response, field: &str) {
+    let vary = match response.headers.get("vary") {
+        Some(vary)
+            if vary
+                .split(',')
+                .any(|v| v.trim().eq_ignore_ascii_case(field) || v.trim() == "*") =>
+        {
+            return;
+        }
+        Some(vary) => format!("{}, {}", vary, field),
+        None => field.to_string(),
+    };
+    response.headers.set("vary", &vary);
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The request used a content coding this server cannot decode.
+    UnsupportedEncoding(String),
+    /// The decoded body would exceed the configured limit.
+    TooLarge,
+    /// The body is not valid for its declared coding.
+    InvalidBody,
+}
+
+impl DecodeError {
+    /// The response to send instead of handling the request: `415` listing
+    /// the codings that are accepted, `413` or `400`.
+    pub fn response(&self) -> Response {
+        match self {
+            DecodeError::UnsupportedEncoding(_) => Response::builder()
+                .status(StatusCode::UnsupportedMediaType)
+                .header("accept-encoding", "gzip, deflate")
+                .build(),
+            DecodeError::TooLarge => Response::builder()
+                .status(StatusCode::ContentTooLarge)
+                .build(),
+            DecodeError::InvalidBody => Response::builder().status(StatusCode::BadRequest).build(),
+        }
+    }
+}
+
+/// Opt-in request body decoding, which runs [`decode_request_body`] before
+/// the handler sees a request and answers with [`DecodeError::response`]
+/// when the body cannot be decoded.
+#[derive(Debug, Clone)]
+pub struct Decompression {
+    max_size: usize,
+}
+
+impl Decompression {
+    /// Decodes bodies of up to `max_size` bytes once decoded.
+    pub fn new(max_size: usize) -> Self {
+        Self { max_size }
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+}
+
+/// Decodes a request body sent with `Content-Encoding: gzip` or `deflate`
+/// (or a list of them, undone in reverse order), so handlers see the plain
+/// body. Afterwards `content-encoding` is removed and `content-length`
+/// matches the decoded body. Decoding stops with `DecodeError::TooLarge`
+/// once the output passes `max_size` bytes, which guards against
+/// decompression bombs.
+pub fn decode_request_body(request: &mut Request, max_size: usize) -> Result<(), DecodeError> {
+    let codings: Vec<String> = match request.headers.get("content-encoding") {
+        Some(value) => value
+            .split(',')
+            .map(|coding| coding.trim().to_ascii_lowercase())
+            .filter(|coding| !coding.is_empty() && coding != "identity")
+            .collect(),
+        None => return Ok(()),
+    };
+    if let Some(coding) = codings
+        .iter()
+        .find(|coding| !matches!(coding.as_str(), "gzip" | "x-gzip" | "deflate"))
+    {
+        return Err(DecodeError::UnsupportedEncoding(coding.clone()));
+    }
+
+    let mut body = None;
+    for coding in codings.iter().rev() {
+        let encoded = body.as_deref().unwrap_or(&request.body[..]);
+        body = Some(match coding.as_str() {
+            "deflate" => decode_limited(ZlibDecoder::new(encoded), max_size)?,
+            _ => decode_limited(GzDecoder::new(encoded), max_size)?,
+        });
+    }
+    let body = body.unwrap_or_else(|| std::mem::take(&mut request.body));
+
+    request.headers.map.remove("content-encoding");
+    request
+        .headers
+        .set("content-length", &body.len().to_string());
+    request.body = body;
+    Ok(())
+}
+
+fn decode_limited<R: Read>(decoder: R, max_size: usize) -> Result<Vec<u8>, DecodeError> {
+    let mut decoded = Vec::new();
+    decoder
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut decoded)
+        .map_err(|_| DecodeError::InvalidBody)?;
+    if decoded.len() > max_size {
+        return Err(DecodeError::TooLarge);
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use std::io::Read;
+
+    fn request(accept_encoding: Option<&str>) -> Request {
+        let mut request = Request::new();
+        if let Some(accept_encoding) = accept_encoding {
+            request.headers.set("Accept-Encoding", accept_encoding);
+        }
+        request
+    }
+
+    fn json_response(len: usize) -> Response {
+        Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .header("ETag", "\"v1\"")
+            .body(vec![b'a'; len])
+            .build()
+    }
+
+    fn body_bytes(response: Response) -> Vec<u8> {
+        match response.body {
+            Body::Bytes(bytes) => bytes,
+            Body::Reader { mut reader, .. } => {
+                let mut bytes = Vec::new();
+                reader.read_to_end(&mut bytes).unwrap();
+                bytes
+            }
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate("gzip, deflate"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip;q=0.5, deflate"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("br, *;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip;q=0, *"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate("gzip;q=0, deflate;q=0"), None);
+        // Weights above 1 or with more than three decimals are malformed.
+        assert_eq!(negotiate("gzip;q=0.5, deflate;q=2"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip;q=1.5"), None);
+        assert_eq!(negotiate("gzip;q=0.0001"), None);
+        assert_eq!(
+            negotiate("gzip;q=1.000, deflate;q=0.999"),
+            Some(Encoding::Gzip)
+        );
+    }
+
+    #[test]
+    fn test_compresses_eligible_bytes() {
+        let response = Compression::new().compress(&request(Some("gzip")), json_response(4096));
+        assert_eq!(response.headers.get("content-encoding"), Some("gzip"));
+        assert_eq!(response.headers.get("vary"), Some("accept-encoding"));
+        assert_eq!(response.headers.get("etag"), Some("W/\"v1\""));
+
+        let mut decoded = Vec::new();
+        GzDecoder::new(&body_bytes(response)[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, vec![b'a'; 4096]);
+    }
+
+    #[test]
+    fn test_compresses_streamed_body() {
+        let response = Response::builder()
+            .header("Content-Type", "text/plain")
+            .body_reader(std::io::Cursor::new(vec![b'z'; 100]), Some(100))
+            .build();
+        let response = Compression::new()
+            .min_size(10)
+            .compress(&request(Some("deflate")), response);
+        assert_eq!(response.headers.get("content-encoding"), Some("deflate"));
+        assert_eq!(response.body.len(), None);
+
+        let mut decoded = Vec::new();
+        ZlibDecoder::new(&body_bytes(response)[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, vec![b'z'; 100]);
+    }
+
+    #[test]
+    fn test_skips_small_bodies_but_varies() {
+        let response = Compression::new().compress(&request(Some("gzip")), json_response(10));
+        assert_eq!(response.headers.get("content-encoding"), None);
+        assert_eq!(response.headers.get("vary"), Some("accept-encoding"));
+        assert_eq!(body_bytes(response).len(), 10);
+    }
+
+    #[test]
+    fn test_skips_ineligible_responses() {
+        let compression = Compression::new();
+        let gzip = request(Some("gzip"));
+
+        let already_encoded = Response::builder()
+            .header("Content-Type", "text/plain")
+            .header("Content-Encoding", "br")
+            .body(vec![b'a'; 4096])
+            .build();
+        let response = compression.compress(&gzip, already_encoded);
+        assert_eq!(response.headers.get("content-encoding"), Some("br"));
+
+        let partial = Response::builder()
+            .status(StatusCode::PartialContent)
+            .header("Content-Type", "text/plain")
+            .header("Content-Range", "bytes 0-4095/10000")
+            .body(vec![b'a'; 4096])
+            .build();
+        let response = compression.compress(&gzip, partial);
+        assert_eq!(response.headers.get("content-encoding"), None);
+
+        let image = Response::builder()
+            .header("Content-Type", "image/png")
+            .body(vec![b'a'; 4096])
+            .build();
+        let response = compression.compress(&gzip, image);
+        assert_eq!(response.headers.get("content-encoding"), None);
+
+        let response = compression.compress(&request(None), json_response(4096));
+        assert_eq!(response.headers.get("content-encoding"), None);
+    }
+
+    fn encoded_request(content_encoding: &str, body: Vec<u8>) -> Request {
+        let mut request = Request::new();
+        request.headers.set("Content-Encoding", content_encoding);
+        request
+            .headers
+            .set("Content-Length", &body.len().to_string());
+        request.body = body;
+        request
+    }
+
+    #[test]
+    fn test_decodes_gzip_request_body() {
+        let compressed =
+            compress_bytes(b"{\"name\":\"upload\"}", Encoding::Gzip, Level::default()).unwrap();
+        let mut request = encoded_request("gzip", compressed);
+
+        decode_request_body(&mut request, 1024).unwrap();
+        assert_eq!(request.body, b"{\"name\":\"upload\"}");
+        assert_eq!(request.headers.get("content-encoding"), None);
+        assert_eq!(request.headers.get("content-length"), Some("17"));
+    }
+
+    #[test]
+    fn test_decodes_stacked_codings_in_reverse() {
+        let inner = compress_bytes(b"hello", Encoding::Deflate, Level::default()).unwrap();
+        let outer = compress_bytes(&inner, Encoding::Gzip, Level::default()).unwrap();
+        let mut request = encoded_request("deflate, gzip", outer);
+
+        decode_request_body(&mut request, 1024).unwrap();
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn test_rejects_decompression_bomb() {
+        let compressed =
+            compress_bytes(&vec![0u8; 1 << 20], Encoding::Gzip, Level::best()).unwrap();
+        let mut request = encoded_request("gzip", compressed);
+
+        let err = decode_request_body(&mut request, 64 * 1024).unwrap_err();
+        assert!(matches!(err, DecodeError::TooLarge));
+        assert_eq!(err.response().status, StatusCode::ContentTooLarge);
+    }
+
+    #[test]
+    fn test_rejects_unsupported_encoding() {
+        let mut request = encoded_request("br", b"...".to_vec());
+
+        let err = decode_request_body(&mut request, 1024).unwrap_err();
+        assert!(matches!(&err, DecodeError::UnsupportedEncoding(coding) if coding == "br"));
+        let response = err.response();
+        assert_eq!(response.status, StatusCode::UnsupportedMediaType);
+        assert_eq!(
+            response.headers.get("accept-encoding"),
+            Some("gzip, deflate")
+        );
+    }
+
+    #[test]
+    fn test_rejects_corrupt_body() {
+        let mut request = encoded_request("gzip", b"not gzip".to_vec());
+        let err = decode_request_body(&mut request, 1024).unwrap_err();
+        assert!(matches!(err, DecodeError::InvalidBody));
+    }
+}
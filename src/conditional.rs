@@ -0,0 +1,358 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::date::HttpDate;
+use crate::headers::Headers;
+
+/// An entity tag as carried by `ETag`, `If-Match`, `If-None-Match` and
+/// `If-Range`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntityTag {
+    pub weak: bool,
+    pub tag: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidEntityTag;
+
+impl EntityTag {
+    pub fn strong(tag: &str) -> Self {
+        Self {
+            weak: false,
+            tag: tag.to_string(),
+        }
+    }
+
+    pub fn weak(tag: &str) -> Self {
+        Self {
+            weak: true,
+            tag: tag.to_string(),
+        }
+    }
+
+    /// Strong comparison: both tags must be strong and identical.
+    pub fn strong_eq(&self, other: &EntityTag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// Weak comparison: the opaque tags must be identical, weakness ignored.
+    pub fn weak_eq(&self, other: &EntityTag) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl fmt::Display for EntityTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.weak {
+            write!(f, "W/")?;
+        }
+        write!(f, "\"{}\"", self.tag)
+    }
+}
+
+impl FromStr for EntityTag {
+    type Err = InvalidEntityTag;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_entity_tag(s.trim()) {
+            Some((tag, "")) => Ok(tag),
+            _ => Err(InvalidEntityTag),
+        }
+    }
+}
+
+// Parses one entity tag from the start of `s`, returning it together with the
+// unparsed remainder.
+fn parse_entity_tag(s: &str) -> Option<(EntityTag, &str)> {
+    let (weak, s) = match s.strip_prefix("W/") {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let s = s.strip_prefix('"')?;
+    let end = s.find('"')?;
+    let tag = &s[..end];
+    let is_valid = tag
+        .bytes()
+        .all(|b| b == 0x21 || (0x23..=0x7e).contains(&b) || b >= 0x80);
+    if !is_valid {
+        return None;
+    }
+    Some((
+        EntityTag {
+            weak,
+            tag: tag.to_string(),
+        },
+        &s[end + 1..],
+    ))
+}
+
+/// The value of an `If-Match` or `If-None-Match` header.
+#[derive(Debug, PartialEq, Eq)]
+pub enum EntityTagList {
+    Any,
+    Tags(Vec<EntityTag>),
+}
+
+impl FromStr for EntityTagList {
+    type Err = InvalidEntityTag;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "*" {
+            return Ok(EntityTagList::Any);
+        }
+
+        // Entity tags may themselves contain commas, so the list is scanned
+        // rather than split.
+        let mut tags = Vec::new();
+        let mut rest = s;
+        loop {
+            rest = rest.trim_start_matches([' ', '\t', ',']);
+            if rest.is_empty() {
+                break;
+            }
+            let (tag, remainder) = parse_entity_tag(rest).ok_or(InvalidEntityTag)?;
+            tags.push(tag);
+            rest = remainder.trim_start_matches([' ', '\t']);
+            if !rest.is_empty() && !rest.starts_with(',') {
+                return Err(InvalidEntityTag);
+            }
+        }
+
+        if tags.is_empty() {
+            return Err(InvalidEntityTag);
+        }
+        Ok(EntityTagList::Tags(tags))
+    }
+}
+
+/// The validators a handler supplies for the selected representation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<EntityTag>,
+    pub last_modified: Option<HttpDate>,
+}
+
+impl Validators {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn etag(mut self, etag: EntityTag) -> Self {
+        self.etag = Some(etag);
+        self
+    }
+
+    pub fn last_modified(mut self, last_modified: HttpDate) -> Self {
+        self.last_modified = Some(last_modified);
+        self
+    }
+
+    /// Adds `ETag` and `Last-Modified` for whichever validators are present.
+    pub fn write_headers(&self, headers: &mut Headers) {
+        if let Some(etag) = &self.etag {
+            headers.set("etag", &etag.to_string());
+        }
+        if let Some(last_modified) = &self.last_modified {
+            headers.set("last-modified", &last_modified.to_string());
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Precondition {
+    /// No precondition failed; the request should be handled normally.
+    Proceed,
+    /// Respond with `304 Not Modified`.
+    NotModified,
+    /// Respond with `412 Precondition Failed`.
+    PreconditionFailed,
+}
+
+/// Evaluates `If-Match`, `If-Unmodified-Since`, `If-None-Match` and
+/// `If-Modified-Since` in the order given by RFC 9110 section 13.2.2.
+/// `If-Range` is left to the range handling that follows.
+pub fn evaluate_preconditions(
+    method: &str,
+    request_headers: &Headers,
+    validators: &Validators,
+) -> Precondition {
+    let is_get_or_head = method == "GET" || method == "HEAD";
+
+    match request_headers.get("if-match") {
+        Some(if_match) => {
+            if !if_match_passes(if_match, validators) {
+                return Precondition::PreconditionFailed;
+            }
+        }
+        None => {
+            if let Some(date) = header_date(request_headers, "if-unmodified-since")
+                && let Some(last_modified) = validators.last_modified
+                && last_modified > date
+            {
+                return Precondition::PreconditionFailed;
+            }
+        }
+    }
+
+    match request_headers.get("if-none-match") {
+        Some(if_none_match) => {
+            if !if_none_match_passes(if_none_match, validators) {
+                return if is_get_or_head {
+                    Precondition::NotModified
+                } else {
+                    Precondition::PreconditionFailed
+                };
+            }
+        }
+        None => {
+            if is_get_or_head
+                && let Some(date) = header_date(request_headers, "if-modified-since")
+                && let Some(last_modified) = validators.last_modified
+                && last_modified <= date
+            {
+                return Precondition::NotModified;
+            }
+        }
+    }
+
+    Precondition::Proceed
+}
+
+fn header_date(request_headers: &Headers, key: &str) -> Option<HttpDate> {
+    // Invalid dates are ignored, as if the header were not sent.
+    request_headers.get(key).and_then(|v| v.parse().ok())
+}
+
+fn if_match_passes(value: &str, validators: &Validators) -> bool {
+    match value.parse::<EntityTagList>() {
+        Ok(EntityTagList::Any) => true,
+        Ok(EntityTagList::Tags(tags)) => match &validators.etag {
+            Some(etag) => tags.iter().any(|tag| tag.strong_eq(etag)),
+            None => false,
+        },
+        Err(_) => false,
+    }
+}
+
+fn if_none_match_passes(value: &str, validators: &Validators) -> bool {
+    match value.parse::<EntityTagList>() {
+        Ok(EntityTagList::Any) => false,
+        Ok(EntityTagList::Tags(tags)) => match &validators.etag {
+            Some(etag) => !tags.iter().any(|tag| tag.weak_eq(etag)),
+            None => true,
+        },
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Headers {
+        let mut headers = Headers::new();
+        for (key, value) in pairs {
+            headers.set(key, value);
+        }
+        headers
+    }
+
+    fn validators() -> Validators {
+        Validators::new()
+            .etag(EntityTag::strong("v1"))
+            .last_modified("Sun, 06 Nov 1994 08:49:37 GMT".parse().unwrap())
+    }
+
+    #[test]
+    fn test_parse_entity_tags() {
+        assert_eq!("\"abc\"".parse(), Ok(EntityTag::strong("abc")));
+        assert_eq!("W/\"abc\"".parse(), Ok(EntityTag::weak("abc")));
+        assert_eq!("abc".parse::<EntityTag>(), Err(InvalidEntityTag));
+        assert_eq!(
+            "\"a,b\", W/\"c\"".parse(),
+            Ok(EntityTagList::Tags(vec![
+                EntityTag::strong("a,b"),
+                EntityTag::weak("c")
+            ]))
+        );
+        assert_eq!(" * ".parse(), Ok(EntityTagList::Any));
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let request = headers(&[("If-None-Match", "W/\"v1\"")]);
+        assert_eq!(
+            evaluate_preconditions("GET", &request, &validators()),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            evaluate_preconditions("PUT", &request, &validators()),
+            Precondition::PreconditionFailed
+        );
+
+        let request = headers(&[("If-None-Match", "\"v2\"")]);
+        assert_eq!(
+            evaluate_preconditions("GET", &request, &validators()),
+            Precondition::Proceed
+        );
+    }
+
+    #[test]
+    fn test_if_match_uses_strong_comparison() {
+        let request = headers(&[("If-Match", "W/\"v1\"")]);
+        assert_eq!(
+            evaluate_preconditions("PUT", &request, &validators()),
+            Precondition::PreconditionFailed
+        );
+        let request = headers(&[("If-Match", "\"v0\", \"v1\"")]);
+        assert_eq!(
+            evaluate_preconditions("PUT", &request, &validators()),
+            Precondition::Proceed
+        );
+    }
+
+    #[test]
+    fn test_if_modified_since() {
+        let request = headers(&[("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")]);
+        assert_eq!(
+            evaluate_preconditions("GET", &request, &validators()),
+            Precondition::NotModified
+        );
+        let request = headers(&[("If-Modified-Since", "Sat, 05 Nov 1994 08:49:37 GMT")]);
+        assert_eq!(
+            evaluate_preconditions("GET", &request, &validators()),
+            Precondition::Proceed
+        );
+    }
+
+    #[test]
+    fn test_if_none_match_takes_precedence_over_if_modified_since() {
+        let request = headers(&[
+            ("If-None-Match", "\"v2\""),
+            ("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT"),
+        ]);
+        assert_eq!(
+            evaluate_preconditions("GET", &request, &validators()),
+            Precondition::Proceed
+        );
+    }
+
+    #[test]
+    fn test_if_unmodified_since() {
+        let request = headers(&[("If-Unmodified-Since", "Sat, 05 Nov 1994 08:49:37 GMT")]);
+        assert_eq!(
+            evaluate_preconditions("DELETE", &request, &validators()),
+            Precondition::PreconditionFailed
+        );
+        // If-Match takes precedence and passes.
+        let request = headers(&[
+            ("If-Match", "\"v1\""),
+            ("If-Unmodified-Since", "Sat, 05 Nov 1994 08:49:37 GMT"),
+        ]);
+        assert_eq!(
+            evaluate_preconditions("DELETE", &request, &validators()),
+            Precondition::Proceed
+        );
+    }
+}
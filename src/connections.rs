@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Shutdown};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Instant;
+
+use crate::net::Stream;
+use crate::server::ServerStats;
+
+/// The connections a server has accepted and not yet finished with, so that
+/// shutdown can wait for them or cut them off.
+#[derive(Debug, Default)]
+pub(crate) struct Connections {
+    closing: AtomicBool,
+    // Connections that finished after closing began.
+    drained: AtomicUsize,
+    next_id: AtomicU64,
+    accepted: AtomicU64,
+    limited: AtomicU64,
+    overloaded: AtomicU64,
+    open: Mutex<Open>,
+    all_closed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Open {
+    tracked: HashMap<u64, Tracked>,
+    // How many of the tracked connections come from each client address.
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+#[derive(Debug)]
+struct Tracked {
+    // A handle on the socket for shutting it down from another thread.
+    stream: Option<Stream>,
+    // Whether a request is being handled, as opposed to waiting for one.
+    busy: bool,
+    ip: Option<IpAddr>,
+}
+
+/// Caps on how many connections may be open at once. `None` is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Limits {
+    pub(crate) total: Option<usize>,
+    pub(crate) per_ip: Option<usize>,
+}
+
+/// The limit that turned a connection away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LimitExceeded {
+    Total,
+    PerIp,
+}
+
+impl Connections {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Starts tracking a connection from `ip` until the returned guard is
+    /// dropped, unless that would go over `limits`. `stream` is a clone of
+    /// the connection's socket, if it has one.
+    pub(crate) fn register(
+        self: &Arc<Self>,
+        stream: Option<Stream>,
+        ip: Option<IpAddr>,
+        limits: Limits,
+    ) -> Result<Connection, LimitExceeded> {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        let mut open = self.lock();
+        let exceeded = if limits.total.is_some_and(|max| open.tracked.len() >= max) {
+            Some(LimitExceeded::Total)
+        } else if let (Some(max), Some(ip)) = (limits.per_ip, ip)
+            && open.per_ip.get(&ip).is_some_and(|&count| count >= max)
+        {
+            Some(LimitExceeded::PerIp)
+        } else {
+            None
+        };
+        if let Some(exceeded) = exceeded {
+            self.limited.fetch_add(1, Ordering::Relaxed);
+            return Err(exceeded);
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        if let Some(ip) = ip {
+            *open.per_ip.entry(ip).or_default() += 1;
+        }
+        open.tracked.insert(
+            id,
+            Tracked {
+                stream,
+                busy: false,
+                ip,
+            },
+        );
+        Ok(Connection {
+            id,
+            connections: Arc::clone(self),
+        })
+    }
+
+    /// Counts a connection turned away because the workers were busy.
+    pub(crate) fn record_overloaded(&self) {
+        self.overloaded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> ServerStats {
+        let open = self.lock();
+        ServerStats {
+            open_connections: open.tracked.len(),
+            busy_connections: open.tracked.values().filter(|t| t.busy).count(),
+            connections_per_ip: open.per_ip.clone(),
+            accepted: self.accepted.load(Ordering::Relaxed),
+            rejected_at_limit: self.limited.load(Ordering::Relaxed),
+            rejected_overloaded: self.overloaded.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    /// Marks the server as closing and closes every connection that is
+    /// waiting for a request rather than handling one.
+    pub(crate) fn begin_close(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+        let open = self.lock();
+        for tracked in open.tracked.values().filter(|tracked| !tracked.busy) {
+            if let Some(stream) = &tracked.stream {
+                let _ = stream.shutdown(Shutdown::Read);
+            }
+        }
+    }
+
+    /// Waits until every connection has finished or `deadline` passes, then
+    /// cuts off whatever is left. Returns how many connections finished
+    /// after closing began and how many were cut off.
+    pub(crate) fn wait_closed(&self, deadline: Instant) -> (usize, usize) {
+        let mut open = self.lock();
+        while !open.tracked.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            open = match self.all_closed.wait_timeout(open, deadline - now) {
+                Ok((open, _)) => open,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+
+        for tracked in open.tracked.values() {
+            if let Some(stream) = &tracked.stream {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+        (self.drained.load(Ordering::SeqCst), open.tracked.len())
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.lock().tracked.len()
+    }
+
+    // A connection thread that panics while holding the lock leaves the maps
+    // themselves intact, so a poisoned lock is still usable.
+    fn lock(&self) -> MutexGuard<'_, Open> {
+        self.open.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A tracked connection. Dropping it marks the connection finished.
+#[derive(Debug)]
+pub(crate) struct Connection {
+    id: u64,
+    connections: Arc<Connections>,
+}
+
+impl Connection {
+    /// Records whether a request is in progress. Returns `false` when the
+    /// server is closing and an idle connection should not wait for another
+    /// request.
+    pub(crate) fn set_busy(&self, busy: bool) -> bool {
+        if let Some(tracked) = self.connections.lock().tracked.get_mut(&self.id) {
+            tracked.busy = busy;
+        }
+        busy || !self.connections.is_closing()
+    }
+
+    pub(crate) fn is_closing(&self) -> bool {
+        self.connections.is_closing()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut open = self.connections.lock();
+        let ip = open.tracked.remove(&self.id).and_then(|tracked| tracked.ip);
+        if let Some(ip) = ip
+            && let Some(count) = open.per_ip.get_mut(&ip)
+        {
+            *count -= 1;
+            if *count == 0 {
+                open.per_ip.remove(&ip);
+            }
+        }
+        if self.connections.is_closing() {
+            self.connections.drained.fetch_add(1, Ordering::SeqCst);
+        }
+        if open.tracked.is_empty() {
+            self.connections.all_closed.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    fn register(connections: &Arc<Connections>) -> Connection {
+        connections.register(None, None, Limits::default()).unwrap()
+    }
+
+    #[test]
+    fn test_dropping_connection_untracks_it() {
+        let connections = Connections::new();
+        let first = register(&connections);
+        let second = register(&connections);
+        assert_eq!(connections.len(), 2);
+        drop(first);
+        assert_eq!(connections.len(), 1);
+        drop(second);
+        assert_eq!(connections.len(), 0);
+    }
+
+    #[test]
+    fn test_idle_connection_stops_once_closing() {
+        let connections = Connections::new();
+        let connection = register(&connections);
+        assert!(connection.set_busy(false));
+        connections.begin_close();
+        assert!(connection.is_closing());
+        assert!(connection.set_busy(true));
+        assert!(!connection.set_busy(false));
+    }
+
+    #[test]
+    fn test_wait_closed_returns_when_connections_finish() {
+        let connections = Connections::new();
+        let connection = register(&connections);
+        connections.begin_close();
+        let worker = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(connection);
+        });
+        let counts = connections.wait_closed(Instant::now() + Duration::from_secs(5));
+        assert_eq!(counts, (1, 0));
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn test_total_limit() {
+        let connections = Connections::new();
+        let limits = Limits {
+            total: Some(2),
+            per_ip: None,
+        };
+        let first = connections.register(None, None, limits).unwrap();
+        let _second = connections.register(None, None, limits).unwrap();
+        assert_eq!(
+            connections.register(None, None, limits).err(),
+            Some(LimitExceeded::Total)
+        );
+        drop(first);
+        assert!(connections.register(None, None, limits).is_ok());
+    }
+
+    #[test]
+    fn test_per_ip_limit() {
+        let connections = Connections::new();
+        let limits = Limits {
+            total: None,
+            per_ip: Some(1),
+        };
+        let a = IpAddr::from([10, 0, 0, 1]);
+        let b = IpAddr::from([10, 0, 0, 2]);
+        let first = connections.register(None, Some(a), limits).unwrap();
+        assert_eq!(
+            connections.register(None, Some(a), limits).err(),
+            Some(LimitExceeded::PerIp)
+        );
+        let _other = connections.register(None, Some(b), limits).unwrap();
+        // Connections without an address, such as over a Unix socket, only
+        // count towards the total.
+        let _unix = connections.register(None, None, limits).unwrap();
+
+        let stats = connections.stats();
+        assert_eq!(stats.open_connections, 3);
+        assert_eq!(stats.connections_per_ip.get(&a), Some(&1));
+        assert_eq!(stats.accepted, 4);
+        assert_eq!(stats.rejected_at_limit, 1);
+
+        drop(first);
+        assert_eq!(connections.stats().connections_per_ip.get(&a), None);
+        assert!(connections.register(None, Some(a), limits).is_ok());
+    }
+
+    #[test]
+    fn test_wait_closed_gives_up_at_deadline() {
+        let connections = Connections::new();
+        let _connection = register(&connections);
+        connections.begin_close();
+        let counts = connections.wait_closed(Instant::now() + Duration::from_millis(20));
+        assert_eq!(counts, (0, 1));
+    }
+}
@@ -0,0 +1,368 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY_NAMES: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+const LONG_DAY_NAMES: [&str; 7] = [
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+];
+const MONTH_NAMES: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// A timestamp with one second resolution as used in HTTP header fields.
+///
+/// Parses all three formats allowed by RFC 9110 (IMF-fixdate, RFC 850 and
+/// asctime) and always formats as IMF-fixdate, e.g.
+/// `Sun, 06 Nov 1994 08:49:37 GMT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HttpDate {
+    secs: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidHttpDate;
+
+impl HttpDate {
+    pub fn now() -> Self {
+        Self::from(SystemTime::now())
+    }
+
+    pub fn from_unix_secs(secs: u64) -> Self {
+        Self { secs }
+    }
+
+    pub fn unix_secs(&self) -> u64 {
+        self.secs
+    }
+
+    /// Formats as in the Common Log Format, e.g.
+    /// `06/Nov/1994:08:49:37 +0000`.
+    pub fn to_common_log(&self) -> String {
+        let (year, month, day, hour, minute, second) = self.parts();
+        format!(
+            "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+            day,
+            MONTH_NAMES[(month - 1) as usize],
+            year,
+            hour,
+            minute,
+            second
+        )
+    }
+
+    /// Formats as an RFC 3339 timestamp in UTC, e.g. `1994-11-06T08:49:37Z`.
+    pub fn to_rfc3339(&self) -> String {
+        let (year, month, day, hour, minute, second) = self.parts();
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year, month, day, hour, minute, second
+        )
+    }
+
+    fn parts(&self) -> (u64, u64, u64, u64, u64, u64) {
+        let secs_of_day = self.secs % SECONDS_PER_DAY;
+        let (year, month, day) = civil_from_days(self.secs / SECONDS_PER_DAY);
+        (
+            year,
+            month,
+            day,
+            secs_of_day / 3600,
+            secs_of_day % 3600 / 60,
+            secs_of_day % 60,
+        )
+    }
+
+    fn from_parts(
+        year: u64,
+        month: u64,
+        day: u64,
+        hour: u64,
+        minute: u64,
+        second: u64,
+    ) -> Result<Self, InvalidHttpDate> {
+        if year < 1970
+            || !(1..=12).contains(&month)
+            || day == 0
+            || day > days_in_month(year, month)
+            || hour > 23
+            || minute > 59
+            || second > 60
+        {
+            return Err(InvalidHttpDate);
+        }
+        let days = days_from_civil(year, month, day);
+        Ok(Self {
+            secs: days * SECONDS_PER_DAY + hour * 3600 + minute * 60 + second.min(59),
+        })
+    }
+}
+
+impl From<SystemTime> for HttpDate {
+    fn from(time: SystemTime) -> Self {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Self { secs }
+    }
+}
+
+impl From<HttpDate> for SystemTime {
+    fn from(date: HttpDate) -> Self {
+        UNIX_EPOCH + Duration::from_secs(date.secs)
+    }
+}
+
+impl fmt::Display for HttpDate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days = self.secs / SECONDS_PER_DAY;
+        let secs_of_day = self.secs % SECONDS_PER_DAY;
+        let (year, month, day) = civil_from_days(days);
+        // 1970-01-01 was a Thursday.
+        let weekday = DAY_NAMES[((days + 3) % 7) as usize];
+        write!(
+            f,
+            "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+            weekday,
+            day,
+            MONTH_NAMES[(month - 1) as usize],
+            year,
+            secs_of_day / 3600,
+            secs_of_day % 3600 / 60,
+            secs_of_day % 60
+        )
+    }
+}
+
+impl FromStr for HttpDate {
+    type Err = InvalidHttpDate;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if !s.is_ascii() {
+            return Err(InvalidHttpDate);
+        }
+        parse_imf_fixdate(s)
+            .or_else(|_| parse_rfc850(s))
+            .or_else(|_| parse_asctime(s))
+    }
+}
+
+/// The current time formatted for a `Date` header. The string is cached and
+/// only reformatted when the second changes, since every response needs one.
+pub fn date_header() -> String {
+    static CACHE: Mutex<Option<(u64, String)>> = Mutex::new(None);
+
+    let now = HttpDate::now();
+    let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    match cache.as_ref() {
+        Some((secs, formatted)) if *secs == now.secs => formatted.clone(),
+        _ => {
+            let formatted = now.to_string();
+            *cache = Some((now.secs, formatted.clone()));
+            formatted
+        }
+    }
+}
+
+// Sun, 06 Nov 1994 08:49:37 GMT
+fn parse_imf_fixdate(s: &str) -> Result<HttpDate, InvalidHttpDate> {
+    let (day_name, rest) = s.split_once(", ").ok_or(InvalidHttpDate)?;
+    if !DAY_NAMES.contains(&day_name) {
+        return Err(InvalidHttpDate);
+    }
+    let parts: Vec<&str> = rest.split(' ').collect();
+    if parts.len() != 5 || parts[0].len() != 2 || parts[2].len() != 4 || parts[4] != "GMT" {
+        return Err(InvalidHttpDate);
+    }
+    let (hour, minute, second) = parse_time(parts[3])?;
+    HttpDate::from_parts(
+        parse_number(parts[2])?,
+        parse_month(parts[1])?,
+        parse_number(parts[0])?,
+        hour,
+        minute,
+        second,
+    )
+}
+
+// Sunday, 06-Nov-94 08:49:37 GMT
+fn parse_rfc850(s: &str) -> Result<HttpDate, InvalidHttpDate> {
+    let (day_name, rest) = s.split_once(", ").ok_or(InvalidHttpDate)?;
+    if !LONG_DAY_NAMES.contains(&day_name) {
+        return Err(InvalidHttpDate);
+    }
+    let parts: Vec<&str> = rest.split(' ').collect();
+    if parts.len() != 3 || parts[2] != "GMT" {
+        return Err(InvalidHttpDate);
+    }
+    let date: Vec<&str> = parts[0].split('-').collect();
+    if date.len() != 3 || date[0].len() != 2 || date[2].len() != 2 {
+        return Err(InvalidHttpDate);
+    }
+    let (hour, minute, second) = parse_time(parts[1])?;
+    HttpDate::from_parts(
+        expand_two_digit_year(parse_number(date[2])?),
+        parse_month(date[1])?,
+        parse_number(date[0])?,
+        hour,
+        minute,
+        second,
+    )
+}
+
+// Sun Nov  6 08:49:37 1994
+fn parse_asctime(s: &str) -> Result<HttpDate, InvalidHttpDate> {
+    let parts: Vec<&str> = s.split_ascii_whitespace().collect();
+    if parts.len() != 5 || !DAY_NAMES.contains(&parts[0]) || parts[4].len() != 4 {
+        return Err(InvalidHttpDate);
+    }
+    let (hour, minute, second) = parse_time(parts[3])?;
+    HttpDate::from_parts(
+        parse_number(parts[4])?,
+        parse_month(parts[1])?,
+        parse_number(parts[2])?,
+        hour,
+        minute,
+        second,
+    )
+}
+
+fn parse_time(s: &str) -> Result<(u64, u64, u64), InvalidHttpDate> {
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() != 3 || parts.iter().any(|p| p.len() != 2) {
+        return Err(InvalidHttpDate);
+    }
+    Ok((
+        parse_number(parts[0])?,
+        parse_number(parts[1])?,
+        parse_number(parts[2])?,
+    ))
+}
+
+fn parse_month(s: &str) -> Result<u64, InvalidHttpDate> {
+    MONTH_NAMES
+        .iter()
+        .position(|m| *m == s)
+        .map(|i| i as u64 + 1)
+        .ok_or(InvalidHttpDate)
+}
+
+fn parse_number(s: &str) -> Result<u64, InvalidHttpDate> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(InvalidHttpDate);
+    }
+    s.parse().map_err(|_| InvalidHttpDate)
+}
+
+/// RFC 9110 says a two digit year that appears to be more than 50 years in
+/// the future refers to the most recent matching year in the past.
+fn expand_two_digit_year(year: u64) -> u64 {
+    let (current_year, _, _) = civil_from_days(HttpDate::now().secs / SECONDS_PER_DAY);
+    let mut full = current_year / 100 * 100 + year;
+    if full > current_year + 50 {
+        full -= 100;
+    }
+    full
+}
+
+fn is_leap_year(year: u64) -> bool {
+    (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400)
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01 for a proleptic Gregorian date, after Howard Hinnant's
+// `days_from_civil`. Only dates from 1970 onwards are representable.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE_SECS: u64 = 784_111_777;
+
+    #[test]
+    fn test_format_imf_fixdate() {
+        let date = HttpDate::from_unix_secs(EXAMPLE_SECS);
+        assert_eq!(date.to_string(), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(
+            HttpDate::from_unix_secs(0).to_string(),
+            "Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+    }
+
+    #[test]
+    fn test_format_log_timestamps() {
+        let date = HttpDate::from_unix_secs(EXAMPLE_SECS);
+        assert_eq!(date.to_common_log(), "06/Nov/1994:08:49:37 +0000");
+        assert_eq!(date.to_rfc3339(), "1994-11-06T08:49:37Z");
+    }
+
+    #[test]
+    fn test_parse_all_formats() {
+        let expected = HttpDate::from_unix_secs(EXAMPLE_SECS);
+        assert_eq!("Sun, 06 Nov 1994 08:49:37 GMT".parse(), Ok(expected));
+        assert_eq!("Sunday, 06-Nov-94 08:49:37 GMT".parse(), Ok(expected));
+        assert_eq!("Sun Nov  6 08:49:37 1994".parse(), Ok(expected));
+    }
+
+    #[test]
+    fn test_round_trip_leap_day() {
+        let date: HttpDate = "Thu, 29 Feb 2024 23:59:59 GMT".parse().unwrap();
+        assert_eq!(date.to_string(), "Thu, 29 Feb 2024 23:59:59 GMT");
+    }
+
+    #[test]
+    fn test_parse_invalid_dates() {
+        assert!("Sun, 06 Nov 1994 08:49:37 UTC".parse::<HttpDate>().is_err());
+        assert!("Fri, 30 Feb 2024 00:00:00 GMT".parse::<HttpDate>().is_err());
+        assert!("Sun, 06 Nov 1994 24:00:00 GMT".parse::<HttpDate>().is_err());
+        assert!("yesterday".parse::<HttpDate>().is_err());
+    }
+
+    #[test]
+    fn test_date_header_is_current_imf_fixdate() {
+        let before = HttpDate::now();
+        let date: HttpDate = date_header().parse().unwrap();
+        assert!(date >= before && date.unix_secs() <= before.unix_secs() + 1);
+        assert!(date_header().ends_with(" GMT"));
+    }
+}
@@ -0,0 +1,106 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Typed values attached to a request, at most one per type.
+///
+/// Middleware uses this to hand data such as an authenticated user or a
+/// start time to the handlers behind it. Values are shared rather than
+/// copied when the request is cloned.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores `value`, replacing any earlier value of the same type.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.map.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref::<T>())
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+
+    /// Removes the value of type `T`, returning it if this was the last
+    /// reference to it.
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        let value = self.map.remove(&TypeId::of::<T>())?;
+        Arc::downcast::<T>(value)
+            .ok()
+            .and_then(|value| Arc::try_unwrap(value).ok())
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct User(String);
+
+    #[test]
+    fn test_insert_and_get_by_type() {
+        let mut extensions = Extensions::new();
+        extensions.insert(User("ada".to_string()));
+        extensions.insert(42u32);
+
+        assert_eq!(extensions.get::<User>(), Some(&User("ada".to_string())));
+        assert_eq!(extensions.get::<u32>(), Some(&42));
+        assert_eq!(extensions.get::<u64>(), None);
+        assert_eq!(extensions.len(), 2);
+    }
+
+    #[test]
+    fn test_insert_replaces_same_type() {
+        let mut extensions = Extensions::new();
+        extensions.insert(1u32);
+        extensions.insert(2u32);
+        assert_eq!(extensions.get::<u32>(), Some(&2));
+        assert_eq!(extensions.len(), 1);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut extensions = Extensions::new();
+        extensions.insert(User("ada".to_string()));
+        assert_eq!(extensions.remove::<User>(), Some(User("ada".to_string())));
+        assert!(!extensions.contains::<User>());
+        assert!(extensions.is_empty());
+    }
+
+    #[test]
+    fn test_clone_shares_values() {
+        let mut extensions = Extensions::new();
+        extensions.insert(User("ada".to_string()));
+        let cloned = extensions.clone();
+        assert_eq!(cloned.get::<User>(), Some(&User("ada".to_string())));
+    }
+}
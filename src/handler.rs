@@ -0,0 +1,25 @@
+use crate::request::Request;
+use crate::response::Response;
+
+/// Application code run by the [`Server`](crate::server::Server) for each
+/// request. Any `Fn(&Request) -> Response` that can be shared between
+/// threads is a handler.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: &Request) -> Response;
+
+    /// Like [`handle`](Self::handle), for a caller that owns the request and
+    /// lets the handler change it. Routers and middleware chains use this to
+    /// fill in path parameters and extensions without copying the request.
+    fn handle_mut(&self, request: &mut Request) -> Response {
+        self.handle(request)
+    }
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
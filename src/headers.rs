@@ -4,7 +4,7 @@ use std::str::from_utf8;
 
 use crate::request::RequestError;
 
-#[derive(Debug)]
+#[derive(Debug, Default, Clone)]
 pub struct Headers {
     pub map: HashMap<String, String>,
 }
@@ -15,33 +15,25 @@ impl Headers {
             map: HashMap::new(),
         }
     }
+    /// Parses at most one header line from `data`, returning the number of
+    /// bytes consumed and whether the blank line ending the headers was seen.
     pub fn parse(&mut self, data: &[u8]) -> Result<(usize, bool), RequestError> {
-        let mut total_consumed = 0usize;
-        loop {
-            let (key_value, consumed) = Self::parse_header(&data[total_consumed..])?;
-
-            match key_value {
-                Some((key, value)) => {
-                    match self.map.entry(key) {
-                        Entry::Occupied(mut entry) => {
-                            entry.get_mut().push_str(", ");
-                            entry.get_mut().push_str(&value);
-                        }
-                        Entry::Vacant(entry) => {
-                            let __ = entry.insert(value);
-                        }
+        let (key_value, consumed) = Self::parse_header(data)?;
+
+        match key_value {
+            Some((key, value)) => {
+                match self.map.entry(key) {
+                    Entry::Occupied(mut entry) => {
+                        entry.get_mut().push_str(", ");
+                        entry.get_mut().push_str(&value);
                     }
-                    total_consumed = total_consumed + consumed;
-                }
-                None => {
-                    if consumed > 0 {
-                        total_consumed = total_consumed + consumed;
-                        return Ok((total_consumed, true));
-                    } else {
-                        return Ok((total_consumed, false));
+                    Entry::Vacant(entry) => {
+                        entry.insert(value);
                     }
                 }
+                Ok((consumed, false))
             }
+            None => Ok((consumed, consumed > 0)),
         }
     }
 
@@ -49,6 +41,11 @@ impl Headers {
         self.map.get(&key.to_lowercase()).map(|v| v.as_str())
     }
 
+    /// Sets `key` to `value`, replacing any existing value.
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.map.insert(key.to_lowercase(), value.to_string());
+    }
+
     fn parse_header(data: &[u8]) -> Result<(Option<(String, String)>, usize), RequestError> {
         if let Some(pos) = data.windows(2).position(|w| w == b"\r\n") {
             if pos == 0 {
@@ -103,7 +100,7 @@ mod tests {
         let (n, done) = result.unwrap();
 
         assert_eq!(
-            headers.map.get("Host").map(|s| s.as_str()),
+            headers.get("Host"),
             Some("localhost:42069")
         );
         assert_eq!(n, 23);
@@ -122,7 +119,7 @@ mod tests {
 
         // Value should be trimmed
         assert_eq!(
-            headers.map.get("Host").map(|s| s.as_str()),
+            headers.get("Host"),
             Some("localhost:42069")
         );
         assert_eq!(n, 30);
@@ -143,7 +140,7 @@ mod tests {
         let (n, done) = result.unwrap();
 
         assert_eq!(
-            headers.map.get("Host").map(|s| s.as_str()),
+            headers.get("Host"),
             Some("localhost:42069")
         );
         assert_eq!(n, 37);
@@ -162,7 +159,7 @@ mod tests {
         assert_eq!(n1, 23);
         assert!(!done1);
         assert_eq!(
-            headers.map.get("Host").map(|s| s.as_str()),
+            headers.get("Host"),
             Some("localhost:42069")
         );
 
@@ -176,11 +173,11 @@ mod tests {
 
         // Both headers should be present
         assert_eq!(
-            headers.map.get("Host").map(|s| s.as_str()),
+            headers.get("Host"),
             Some("localhost:42069")
         );
         assert_eq!(
-            headers.map.get("Content-Type").map(|s| s.as_str()),
+            headers.get("Content-Type"),
             Some("application/json")
         );
         assert_eq!(headers.map.len(), 2);
@@ -208,7 +205,7 @@ mod tests {
 
         // Header should still be present
         assert_eq!(
-            headers.map.get("Host").map(|s| s.as_str()),
+            headers.get("Host"),
             Some("localhost:42069")
         );
     }
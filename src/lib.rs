@@ -0,0 +1,25 @@
+pub mod access_log;
+#[cfg(unix)]
+pub mod activation;
+pub mod body;
+pub mod compression;
+pub mod conditional;
+mod connections;
+pub mod date;
+pub mod extensions;
+pub mod handler;
+pub mod headers;
+mod linger;
+pub mod middleware;
+pub mod net;
+pub mod pool;
+pub mod range;
+pub mod rate_limit;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod server;
+pub mod timeouts;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod writer;
@@ -0,0 +1,119 @@
+use std::io::{self, Read};
+use std::net::Shutdown;
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::net::Stream;
+
+// How long a client gets to read an answer and close its side.
+const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_LINGERING: usize = 1024;
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+// Keeps a client that sends faster than it is read from holding up the
+// others, or outliving its deadline.
+const MAX_READS_PER_PASS: usize = 16;
+
+/// Closes connections that were answered without their requests being read.
+///
+/// Closing a TCP socket with unread input makes the kernel send a reset,
+/// which can destroy the answer before the client reads it. Instead, the
+/// write side is shut down so the client sees the end of the answer, and a
+/// background thread reads and discards whatever the client still sends
+/// until it closes too or [`LINGER_TIMEOUT`] passes.
+pub(crate) struct Linger {
+    sender: SyncSender<Stream>,
+}
+
+impl Linger {
+    pub(crate) fn new() -> Self {
+        let (sender, receiver) = mpsc::sync_channel(MAX_LINGERING);
+        thread::spawn(move || drain(receiver));
+        Self { sender }
+    }
+
+    /// Closes `stream` once the client has finished sending. When too many
+    /// connections are already lingering, it is closed straight away.
+    pub(crate) fn close(&self, stream: Stream) {
+        let _ = stream.shutdown(Shutdown::Write);
+        let _ = self.sender.try_send(stream);
+    }
+}
+
+/// Drains lingering connections until every [`Linger`] handle is gone and
+/// the last of them has closed.
+fn drain(receiver: Receiver<Stream>) {
+    let mut lingering: Vec<(Stream, Instant)> = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        // Block while there is nothing to do, then pick up everything queued.
+        let mut next = if lingering.is_empty() {
+            match receiver.recv() {
+                Ok(stream) => Some(stream),
+                Err(_) => return,
+            }
+        } else {
+            None
+        };
+        loop {
+            if let Some(stream) = next.take()
+                && stream.set_nonblocking(true).is_ok()
+            {
+                lingering.push((stream, Instant::now() + LINGER_TIMEOUT));
+            }
+            match receiver.try_recv() {
+                Ok(stream) => next = Some(stream),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) if lingering.is_empty() => return,
+                Err(TryRecvError::Disconnected) => break,
+            }
+        }
+
+        let now = Instant::now();
+        lingering.retain_mut(|(stream, deadline)| now < *deadline && discard(stream, &mut buffer));
+        if !lingering.is_empty() {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Reads what `stream` has to offer, up to [`MAX_READS_PER_PASS`] reads,
+/// returning whether the client may still send more.
+fn discard(stream: &mut Stream, buffer: &mut [u8]) -> bool {
+    for _ in 0..MAX_READS_PER_PASS {
+        match stream.read(buffer) {
+            Ok(0) => return false,
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => return false,
+        }
+    }
+    true
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_discard_stops_after_a_few_reads() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.set_nonblocking(true).unwrap();
+        let chunk = [0u8; 4096];
+        let mut sent = 0;
+        while let Ok(n) = client.write(&chunk) {
+            sent += n;
+        }
+        assert!(sent > MAX_READS_PER_PASS * chunk.len());
+
+        let mut stream = Stream::Unix(server);
+        stream.set_nonblocking(true).unwrap();
+        let mut buffer = [0u8; 4096];
+        assert!(discard(&mut stream, &mut buffer));
+        // What the client sent beyond the cap is left for the next pass.
+        assert!(stream.read(&mut buffer).unwrap() > 0);
+    }
+}
@@ -1,17 +1,123 @@
-use crate::{request::request_from_reader, server::Server};
-use std::net::TcpListener;
-use ctrlc;
+use http_from_tcp::access_log::{AccessLog, LogFormat};
+use http_from_tcp::request::Request;
+use http_from_tcp::response::{Response, StatusCode};
+use http_from_tcp::router::Router;
+use http_from_tcp::server::{Server, ServerConfig};
+#[cfg(feature = "tls")]
+use http_from_tcp::tls::{CaBundle, Certificate, ClientAuth, TlsConfig};
+use std::error::Error;
 use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
-mod headers;
-mod request;
-mod server;
-mod response;
 
-const PORT: u16 = 42069;
+const DEFAULT_ADDR: &str = "127.0.0.1:42069";
 
-fn main() {
-    let server = Server::serve(PORT).unwrap();
+fn your_problem(_: &Request) -> Response {
+    Response::builder()
+        .status(StatusCode::BadRequest)
+        .body("Your problem is not my problem\n")
+        .build()
+}
+
+fn my_problem(_: &Request) -> Response {
+    Response::builder()
+        .status(StatusCode::InternalServerError)
+        .body("Woopsie, my bad\n")
+        .build()
+}
+
+fn all_good(_: &Request) -> Response {
+    Response::builder().body("All good\n").build()
+}
+
+fn router() -> Router {
+    Router::new()
+        .get("/yourproblem", your_problem)
+        .get("/myproblem", my_problem)
+        .get("/", all_good)
+        .get("/*path", all_good)
+}
+
+fn config() -> Result<ServerConfig, Box<dyn Error>> {
+    let mut config = ServerConfig::new();
+    if let Some(access_log) = access_log()? {
+        config = config.access_log(access_log);
+    }
+    tls(config)
+}
+
+/// Logs requests when `ACCESS_LOG` names a file, or `-` for stdout, in the
+/// `ACCESS_LOG_FORMAT` given: `common` (the default), `combined` or `json`.
+/// A file is rotated once it reaches `ACCESS_LOG_MAX_SIZE` bytes, if set,
+/// and reopened on `SIGHUP`.
+fn access_log() -> Result<Option<AccessLog>, Box<dyn Error>> {
+    let Some(target) = std::env::var_os("ACCESS_LOG") else {
+        return Ok(None);
+    };
+    let mut access_log = if target == "-" {
+        AccessLog::stdout()
+    } else {
+        AccessLog::file(target)?
+    };
+    if let Ok(max_size) = std::env::var("ACCESS_LOG_MAX_SIZE") {
+        access_log = access_log.max_size(Some(max_size.parse()?));
+    }
+    let format = match std::env::var("ACCESS_LOG_FORMAT").as_deref() {
+        Ok("common") | Err(_) => LogFormat::Common,
+        Ok("combined") => LogFormat::Combined,
+        Ok("json") => LogFormat::Json,
+        Ok(other) => return Err(format!("Unknown ACCESS_LOG_FORMAT: {}", other).into()),
+    };
+    access_log = access_log.format(format);
+    #[cfg(unix)]
+    let access_log = access_log.reopen_on_sighup()?;
+    Ok(Some(access_log))
+}
+
+/// Serves HTTPS when `TLS_CERT` and `TLS_KEY` name PEM files. Clients must
+/// then present a certificate issued by the CAs in `TLS_CLIENT_CA`, if set,
+/// unless `TLS_CLIENT_AUTH` is `optional`.
+#[cfg(feature = "tls")]
+fn tls(config: ServerConfig) -> Result<ServerConfig, Box<dyn Error>> {
+    let (Some(cert), Some(key)) = (std::env::var_os("TLS_CERT"), std::env::var_os("TLS_KEY"))
+    else {
+        return Ok(config);
+    };
+    let mut tls = TlsConfig::new(Certificate::from_pem_files(cert, key)?);
+    if let Some(ca) = std::env::var_os("TLS_CLIENT_CA") {
+        let bundle = CaBundle::from_pem_file(ca)?;
+        tls = match std::env::var("TLS_CLIENT_AUTH").as_deref() {
+            Ok("optional") => tls.client_auth(ClientAuth::Optional(bundle)),
+            Ok("required") | Err(_) => tls.client_auth(ClientAuth::Required(bundle)),
+            Ok(other) => return Err(format!("Unknown TLS_CLIENT_AUTH mode: {}", other).into()),
+        };
+    }
+    Ok(config.tls(tls))
+}
 
+#[cfg(not(feature = "tls"))]
+fn tls(config: ServerConfig) -> Result<ServerConfig, Box<dyn Error>> {
+    Ok(config)
+}
+
+fn start() -> Result<Server, Box<dyn Error>> {
+    let config = config()?;
+    // A listener passed in by socket activation takes precedence.
+    if let Some(server) = Server::from_activation(config.clone(), router())? {
+        return Ok(server);
+    }
+
+    // The listen address may be given as the first argument, e.g. `[::]:8080`
+    // or `unix:/run/http.sock`.
+    let addr = std::env::args().nth(1).unwrap_or_else(|| DEFAULT_ADDR.to_string());
+    let server = match addr.strip_prefix("unix:") {
+        Some(path) => Server::bind_unix_with_config(path, config, router())?,
+        None => Server::bind_with_config(addr.as_str(), config, router())?,
+    };
+    Ok(server)
+}
+
+fn main() {
+    // Installed first so a signal that arrives while starting up still
+    // shuts the server down cleanly.
     let running = Arc::new(AtomicBool::new(true));
 
     let running_clone = Arc::clone(&running);
@@ -19,12 +125,28 @@ fn main() {
         running_clone.store(false, Ordering::SeqCst);
     }).expect("Error setting Ctrl-C handler");
 
+    let server = match start() {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    match (server.local_addr(), server.unix_path()) {
+        (Some(addr), _) => println!("Listening on {}", addr),
+        (None, Some(path)) => println!("Listening on {}", path.display()),
+        (None, None) => {}
+    }
+
     while running.load(Ordering::SeqCst) {
         std::thread::sleep(std::time::Duration::from_millis(100));
     }
 
     println!("Shutting down server...");
-    server.close();
-    println!("Server stopped");
+    let report = server.close();
+    println!(
+        "Server stopped: {} connections drained, {} aborted in {:?}",
+        report.drained, report.aborted, report.elapsed
+    );
 
 }
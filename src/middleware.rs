@@ -0,0 +1,291 @@
+use std::sync::Arc;
+
+use crate::compression::{Compression, Decompression, decode_request_body};
+use crate::handler::Handler;
+use crate::rate_limit::RateLimit;
+use crate::request::Request;
+use crate::response::Response;
+
+/// Code that runs around a handler, for concerns such as logging, auth or
+/// compression that apply to many routes.
+///
+/// `before` sees the request first and may change it, for example by adding
+/// [`extensions`](crate::extensions::Extensions), or answer it outright by
+/// returning a response. `after` sees every response on its way out.
+pub trait Middleware: Send + Sync + 'static {
+    fn before(&self, _request: &mut Request) -> Option<Response> {
+        None
+    }
+
+    fn after(&self, _request: &Request, response: Response) -> Response {
+        response
+    }
+}
+
+/// A handler wrapped in layers of middleware.
+///
+/// Each call to [`layer`](Chain::layer) wraps everything added so far, so the
+/// last layer is the outermost: its `before` runs first and its `after` runs
+/// last. When a `before` hook returns a response, the layers inside it and
+/// the handler are skipped, and the response goes back out through the
+/// `after` hooks of the layers outside it.
+pub struct Chain {
+    handler: Box<dyn Handler>,
+    // Innermost first.
+    layers: Vec<Arc<dyn Middleware>>,
+}
+
+impl Chain {
+    pub fn new(handler: impl Handler) -> Self {
+        Self::from_boxed(Box::new(handler))
+    }
+
+    pub(crate) fn from_boxed(handler: Box<dyn Handler>) -> Self {
+        Self {
+            handler,
+            layers: Vec::new(),
+        }
+    }
+
+    pub fn layer(self, middleware: impl Middleware) -> Self {
+        self.layer_shared(Arc::new(middleware))
+    }
+
+    pub(crate) fn layer_shared(mut self, middleware: Arc<dyn Middleware>) -> Self {
+        self.layers.push(middleware);
+        self
+    }
+}
+
+impl Handler for Chain {
+    fn handle(&self, request: &Request) -> Response {
+        self.handle_mut(&mut request.clone())
+    }
+
+    fn handle_mut(&self, request: &mut Request) -> Response {
+        // Walk inwards from the outermost layer until one answers.
+        let mut entered = 0;
+        let mut response = None;
+        for middleware in self.layers.iter().rev() {
+            if let Some(early) = middleware.before(request) {
+                response = Some(early);
+                break;
+            }
+            entered += 1;
+        }
+        let mut response = response.unwrap_or_else(|| self.handler.handle_mut(request));
+
+        // Then back out through the layers whose `before` let it pass.
+        let outer = self.layers.len() - entered;
+        for middleware in &self.layers[outer..] {
+            response = middleware.after(request, response);
+        }
+        response
+    }
+}
+
+impl Middleware for Compression {
+    fn after(&self, request: &Request, response: Response) -> Response {
+        self.compress(request, response)
+    }
+}
+
+impl Middleware for Decompression {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        decode_request_body(request, self.max_size())
+            .err()
+            .map(|e| e.response())
+    }
+}
+
+impl Middleware for RateLimit {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        self.admit(request)
+    }
+
+    fn after(&self, request: &Request, response: Response) -> Response {
+        self.add_headers(request, response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::Body;
+    use crate::request::request_from_reader;
+    use crate::response::StatusCode;
+    use crate::router::Router;
+    use std::io::Cursor;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    fn request(target: &str, extra: &str) -> Request {
+        let raw = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+            target, extra
+        );
+        request_from_reader(&mut Cursor::new(raw.into_bytes())).unwrap()
+    }
+
+    fn body(response: &Response) -> String {
+        match &response.body {
+            Body::Bytes(bytes) => String::from_utf8(bytes.clone()).unwrap(),
+            other => panic!("unexpected body {:?}", other),
+        }
+    }
+
+    /// Records the order in which its hooks run.
+    struct Trace {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+        answer: bool,
+    }
+
+    impl Middleware for Trace {
+        fn before(&self, _request: &mut Request) -> Option<Response> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("before {}", self.name));
+            if self.answer {
+                return Some(Response::builder().status(StatusCode::Unauthorized).build());
+            }
+            None
+        }
+
+        fn after(&self, _request: &Request, response: Response) -> Response {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("after {}", self.name));
+            response
+        }
+    }
+
+    fn trace(name: &'static str, log: &Arc<Mutex<Vec<String>>>, answer: bool) -> Trace {
+        Trace {
+            name,
+            log: Arc::clone(log),
+            answer,
+        }
+    }
+
+    struct User(String);
+
+    struct Auth;
+
+    impl Middleware for Auth {
+        fn before(&self, request: &mut Request) -> Option<Response> {
+            let token = request.headers.get("Authorization")?.to_string();
+            request.extensions.insert(User(token));
+            None
+        }
+    }
+
+    #[test]
+    fn test_last_layer_is_outermost() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let handler_log = Arc::clone(&log);
+        let chain = Chain::new(move |_: &Request| {
+            handler_log.lock().unwrap().push("handler".to_string());
+            Response::builder().build()
+        })
+        .layer(trace("inner", &log, false))
+        .layer(trace("outer", &log, false));
+
+        chain.handle(&request("/", ""));
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "before outer",
+                "before inner",
+                "handler",
+                "after inner",
+                "after outer"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_before_can_short_circuit() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let chain = Chain::new(|_: &Request| -> Response { panic!("handler must not run") })
+            .layer(trace("inner", &log, false))
+            .layer(trace("guard", &log, true))
+            .layer(trace("outer", &log, false));
+
+        let response = chain.handle(&request("/", ""));
+        assert_eq!(response.status, StatusCode::Unauthorized);
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["before outer", "before guard", "after outer"]
+        );
+    }
+
+    #[test]
+    fn test_extensions_reach_the_handler() {
+        let chain = Chain::new(|request: &Request| {
+            let user = request
+                .extensions
+                .get::<User>()
+                .map_or("anonymous", |u| &u.0);
+            Response::builder().body(format!("hello {}", user)).build()
+        })
+        .layer(Auth);
+
+        let response = chain.handle(&request("/", "Authorization: ada\r\n"));
+        assert_eq!(body(&response), "hello ada");
+        let response = chain.handle(&request("/", ""));
+        assert_eq!(body(&response), "hello anonymous");
+    }
+
+    #[test]
+    fn test_router_layer_wraps_existing_routes() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let admin = Router::new()
+            .get("/stats", |_: &Request| Response::builder().build())
+            .layer(trace("admin", &log, true));
+        let router = Router::new()
+            .get("/", |_: &Request| Response::builder().build())
+            .nest("/admin", admin);
+
+        assert_eq!(router.handle(&request("/", "")).status, StatusCode::OK);
+        assert!(log.lock().unwrap().is_empty());
+        assert_eq!(
+            router.handle(&request("/admin/stats", "")).status,
+            StatusCode::Unauthorized
+        );
+    }
+
+    #[test]
+    fn test_compression_as_middleware() {
+        let chain = Chain::new(|_: &Request| {
+            Response::builder()
+                .header("content-type", "text/plain")
+                .body("a".repeat(4096))
+                .build()
+        })
+        .layer(Compression::new());
+
+        let response = chain.handle(&request("/", "Accept-Encoding: gzip\r\n"));
+        assert_eq!(response.headers.get("content-encoding"), Some("gzip"));
+    }
+
+    #[test]
+    fn test_rate_limit_as_middleware() {
+        let chain = Chain::new(|_: &Request| Response::builder().body("ok").build()).layer(
+            RateLimit::new(2, Duration::from_secs(60))
+                .key(|request| request.headers.get("x-api-key").map(str::to_string)),
+        );
+
+        let api_request = || request("/", "X-Api-Key: one\r\n");
+        let response = chain.handle(&api_request());
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.headers.get("ratelimit-remaining"), Some("1"));
+        chain.handle(&api_request());
+        let response = chain.handle(&api_request());
+        assert_eq!(response.status, StatusCode::TooManyRequests);
+        assert_eq!(response.headers.get("retry-after"), Some("30"));
+        assert_eq!(response.headers.get("ratelimit-reset"), Some("60"));
+    }
+}
@@ -0,0 +1,341 @@
+use std::io::{self, Read, Write};
+#[cfg(not(unix))]
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(not(unix))]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use socket2::SockRef;
+
+use crate::timeouts::SocketTimeouts;
+
+/// Details of the connection a request arrived on. The server adds one to
+/// every request's [`extensions`](crate::extensions::Extensions).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ConnectionInfo {
+    /// The client's address. `None` on a Unix socket.
+    pub peer_addr: Option<SocketAddr>,
+    /// The server address the client connected to. `None` on a Unix socket.
+    pub local_addr: Option<SocketAddr>,
+    /// The identity of the process on the other end of a Unix socket, where
+    /// the OS reports it.
+    pub peer_credentials: Option<PeerCredentials>,
+    /// The negotiated TLS details, when the server terminates TLS.
+    #[cfg(feature = "tls")]
+    pub tls: Option<crate::tls::TlsInfo>,
+}
+
+/// The user, group and process of a Unix socket peer, as reported by the
+/// kernel when it connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+}
+
+/// A socket the server accepts connections on.
+#[derive(Debug)]
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Readies the listener for [`Listener::accept`], which on Unix waits
+    /// for connections without blocking in the accept call itself.
+    pub(crate) fn prepare(&self) -> io::Result<()> {
+        let nonblocking = cfg!(unix);
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Waits for the next connection. `None` once `waker` has been woken.
+    #[cfg(unix)]
+    pub(crate) fn accept(&self, waker: &Waker) -> io::Result<Option<(Stream, ConnectionInfo)>> {
+        let fd = match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        };
+        loop {
+            let mut fds = [
+                libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: waker.wait.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+            // SAFETY: `fds` is a valid array of two pollfds for the duration
+            // of the call.
+            if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            if fds[1].revents != 0 {
+                return Ok(None);
+            }
+            // Another process sharing the socket may have taken the
+            // connection first.
+            match self.accept_ready() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                accepted => return accepted.map(Some),
+            }
+        }
+    }
+
+    /// Waits for the next connection. `None` once `waker` has been woken.
+    #[cfg(not(unix))]
+    pub(crate) fn accept(&self, waker: &Waker) -> io::Result<Option<(Stream, ConnectionInfo)>> {
+        let accepted = self.accept_ready();
+        if waker.woken.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        accepted.map(Some)
+    }
+
+    fn accept_ready(&self) -> io::Result<(Stream, ConnectionInfo)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer_addr) = listener.accept()?;
+                // Some platforms pass the listener's non-blocking mode on.
+                stream.set_nonblocking(false)?;
+                let info = ConnectionInfo {
+                    peer_addr: Some(peer_addr),
+                    local_addr: stream.local_addr().ok(),
+                    ..ConnectionInfo::default()
+                };
+                Ok((Stream::Tcp(stream), info))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                let info = ConnectionInfo {
+                    peer_credentials: peer_credentials(&stream),
+                    ..ConnectionInfo::default()
+                };
+                Ok((Stream::Unix(stream), info))
+            }
+        }
+    }
+}
+
+/// Interrupts a [`Listener::accept`] waiting on another thread. On Unix
+/// this goes through a socket pair of its own rather than the listener,
+/// which may be shared with other processes that would take a wake-up
+/// connection for themselves.
+#[cfg(unix)]
+#[derive(Debug)]
+pub(crate) struct Waker {
+    signal: UnixStream,
+    wait: UnixStream,
+}
+
+#[cfg(unix)]
+impl Waker {
+    pub(crate) fn new(_: &Listener) -> io::Result<Self> {
+        let (signal, wait) = UnixStream::pair()?;
+        Ok(Self { signal, wait })
+    }
+
+    pub(crate) fn wake(&self) {
+        let _ = (&self.signal).write(&[1]);
+    }
+}
+
+/// Interrupts a [`Listener::accept`] waiting on another thread by
+/// connecting to the listener.
+#[cfg(not(unix))]
+#[derive(Debug)]
+pub(crate) struct Waker {
+    addr: SocketAddr,
+    woken: AtomicBool,
+}
+
+#[cfg(not(unix))]
+impl Waker {
+    pub(crate) fn new(listener: &Listener) -> io::Result<Self> {
+        let Listener::Tcp(listener) = listener;
+        Ok(Self {
+            addr: wake_addr(listener.local_addr()?),
+            woken: AtomicBool::new(false),
+        })
+    }
+
+    pub(crate) fn wake(&self) {
+        self.woken.store(true, Ordering::SeqCst);
+        let _ = TcpStream::connect_timeout(&self.addr, Duration::from_secs(1));
+    }
+}
+
+/// The address to connect to in order to reach a listener bound to `addr`,
+/// which may be a wildcard address.
+#[cfg(not(unix))]
+fn wake_addr(addr: SocketAddr) -> SocketAddr {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    SocketAddr::new(ip, addr.port())
+}
+
+/// One accepted connection, over TCP or a Unix socket.
+#[derive(Debug)]
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    /// Closes the connection abruptly, so that a TCP client sees a reset
+    /// rather than an orderly close.
+    pub(crate) fn reset(self) {
+        if let Stream::Tcp(stream) = &self {
+            // A zero linger time makes close send RST and drop unsent data.
+            let _ = SockRef::from(stream).set_linger(Some(Duration::ZERO));
+        }
+    }
+
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl SocketTimeouts for Stream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_credentials(stream: &UnixStream) -> Option<PeerCredentials> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: `cred` and `len` are valid for writes and `len` holds the size
+    // of `cred`, as SO_PEERCRED requires.
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if result != 0 {
+        eprintln!(
+            "Error reading peer credentials: {}",
+            io::Error::last_os_error()
+        );
+        return None;
+    }
+    Some(PeerCredentials {
+        uid: cred.uid,
+        gid: cred.gid,
+        pid: Some(cred.pid),
+    })
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn peer_credentials(_: &UnixStream) -> Option<PeerCredentials> {
+    None
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unix_peer_credentials_are_our_own() {
+        let (ours, _theirs) = UnixStream::pair().unwrap();
+        let cred = peer_credentials(&ours).unwrap();
+        // SAFETY: these calls only read the process's own ids.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        assert_eq!(cred.uid, uid);
+        assert_eq!(cred.gid, gid);
+        assert_eq!(cred.pid, Some(std::process::id() as i32));
+    }
+}
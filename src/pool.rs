@@ -0,0 +1,201 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// How often a blocked `submit_while` checks whether to keep waiting.
+const SUBMIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A fixed number of worker threads fed from a bounded queue. Each item
+/// submitted is passed to the same `work` function on whichever worker is
+/// free next.
+///
+/// Dropping the pool stops taking new items, lets the workers finish what is
+/// already queued, and waits for them to exit.
+pub struct ThreadPool<T: Send + 'static> {
+    sender: Option<SyncSender<T>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> ThreadPool<T> {
+    /// Starts `size` workers sharing a queue that holds up to `queue_size`
+    /// items waiting for a worker. Both must be at least one.
+    pub fn new(size: usize, queue_size: usize, work: impl Fn(T) + Send + Sync + 'static) -> Self {
+        assert!(size > 0, "a thread pool needs at least one worker");
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let work = Arc::new(work);
+
+        let workers = (0..size)
+            .map(|id| {
+                let receiver = Arc::clone(&receiver);
+                let work = Arc::clone(&work);
+                thread::Builder::new()
+                    .name(format!("http-worker-{}", id))
+                    .spawn(move || run_worker(&receiver, work.as_ref()))
+                    .expect("failed to spawn worker thread")
+            })
+            .collect();
+
+        Self {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// Queues `item`, waiting for room if the queue is full. The item is
+    /// handed back if the workers have gone away.
+    pub fn submit(&self, item: T) -> Result<(), T> {
+        match &self.sender {
+            Some(sender) => sender.send(item).map_err(|e| e.0),
+            None => Err(item),
+        }
+    }
+
+    /// Like [`submit`](Self::submit), but while the queue is full keeps
+    /// waiting only as long as `wait` returns `true`, handing `item` back
+    /// once it returns `false`.
+    pub fn submit_while(&self, mut item: T, wait: impl Fn() -> bool) -> Result<(), T> {
+        let Some(sender) = &self.sender else {
+            return Err(item);
+        };
+        loop {
+            match sender.try_send(item) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(item)) => return Err(item),
+                Err(TrySendError::Full(full)) => {
+                    if !wait() {
+                        return Err(full);
+                    }
+                    item = full;
+                    thread::sleep(SUBMIT_POLL_INTERVAL);
+                }
+            }
+        }
+    }
+
+    /// Queues `item` only if there is room right now, otherwise hands it back.
+    pub fn try_submit(&self, item: T) -> Result<(), T> {
+        match &self.sender {
+            Some(sender) => sender.try_send(item).map_err(|e| match e {
+                TrySendError::Full(item) | TrySendError::Disconnected(item) => item,
+            }),
+            None => Err(item),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+}
+
+fn run_worker<T>(receiver: &Mutex<Receiver<T>>, work: &(impl Fn(T) + ?Sized)) {
+    loop {
+        // The lock is released as soon as an item is taken, so the other
+        // workers can pick up the next one while this one runs.
+        let item = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        let Ok(item) = item else {
+            return;
+        };
+        // A panicking item must not shrink the pool.
+        if panic::catch_unwind(AssertUnwindSafe(|| work(item))).is_err() {
+            eprintln!("Worker thread recovered from a panic");
+        }
+    }
+}
+
+impl<T: Send + 'static> Drop for ThreadPool<T> {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    #[test]
+    fn test_runs_every_item() {
+        let (done, results) = channel();
+        let done = Mutex::new(done);
+        let pool = ThreadPool::new(4, 8, move |n: u32| {
+            done.lock().unwrap().send(n * 2).unwrap();
+        });
+        for n in 0..20 {
+            pool.submit(n).unwrap();
+        }
+        drop(pool);
+
+        let mut results: Vec<u32> = results.try_iter().collect();
+        results.sort();
+        assert_eq!(results, (0..20).map(|n| n * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_try_submit_hands_back_item_when_full() {
+        let (release, gate) = channel::<()>();
+        let gate = Mutex::new(gate);
+        let (started, wait_started) = channel();
+        let started = Mutex::new(started);
+        let pool = ThreadPool::new(1, 1, move |_: u32| {
+            started.lock().unwrap().send(()).unwrap();
+            gate.lock().unwrap().recv().unwrap();
+        });
+
+        // One item occupies the worker, one fills the queue.
+        pool.submit(1).unwrap();
+        wait_started.recv_timeout(Duration::from_secs(5)).unwrap();
+        pool.submit(2).unwrap();
+        assert_eq!(pool.try_submit(3), Err(3));
+
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_submit_while_gives_up_when_told() {
+        let (release, gate) = channel::<()>();
+        let gate = Mutex::new(gate);
+        let (started, wait_started) = channel();
+        let started = Mutex::new(started);
+        let pool = ThreadPool::new(1, 1, move |_: u32| {
+            started.lock().unwrap().send(()).unwrap();
+            gate.lock().unwrap().recv().unwrap();
+        });
+
+        pool.submit(1).unwrap();
+        wait_started.recv_timeout(Duration::from_secs(5)).unwrap();
+        pool.submit(2).unwrap();
+        let polls = std::sync::atomic::AtomicUsize::new(0);
+        let keep_waiting = || polls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) < 3;
+        assert_eq!(pool.submit_while(3, keep_waiting), Err(3));
+        assert_eq!(polls.into_inner(), 4);
+
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_panicking_item_keeps_worker_alive() {
+        let (done, results) = channel();
+        let done = Mutex::new(done);
+        let pool = ThreadPool::new(1, 4, move |n: u32| {
+            if n == 0 {
+                panic!("bad item");
+            }
+            done.lock().unwrap().send(n).unwrap();
+        });
+        pool.submit(0).unwrap();
+        pool.submit(1).unwrap();
+        assert_eq!(results.recv_timeout(Duration::from_secs(5)), Ok(1));
+    }
+}
@@ -0,0 +1,286 @@
+use crate::conditional::{EntityTag, Validators};
+use crate::date::HttpDate;
+
+const BYTES_UNIT: &str = "bytes=";
+// More ranges than this in one header is treated as abuse and ignored.
+const MAX_RANGES: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeSpec {
+    /// `bytes=first-last`
+    FromTo(u64, u64),
+    /// `bytes=first-`
+    From(u64),
+    /// `bytes=-suffix_length`
+    Suffix(u64),
+}
+
+impl RangeSpec {
+    /// Resolves the spec against a representation of `len` bytes, returning
+    /// `None` when it does not overlap the representation at all.
+    pub fn resolve(&self, len: u64) -> Option<ByteRange> {
+        match *self {
+            RangeSpec::FromTo(first, last) => {
+                if first >= len {
+                    return None;
+                }
+                Some(ByteRange {
+                    start: first,
+                    end: last.min(len - 1),
+                })
+            }
+            RangeSpec::From(first) => {
+                if first >= len {
+                    return None;
+                }
+                Some(ByteRange {
+                    start: first,
+                    end: len - 1,
+                })
+            }
+            RangeSpec::Suffix(suffix) => {
+                if suffix == 0 || len == 0 {
+                    return None;
+                }
+                Some(ByteRange {
+                    start: len.saturating_sub(suffix),
+                    end: len - 1,
+                })
+            }
+        }
+    }
+}
+
+/// An inclusive range of byte offsets that lies within the representation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        (self.end + 1).saturating_sub(self.start)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.end < self.start
+    }
+
+    /// The `Content-Range` value for this range of a `complete_len` byte
+    /// representation.
+    pub fn content_range(&self, complete_len: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, complete_len)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeError {
+    /// The header is malformed or uses a unit other than bytes; it should be
+    /// ignored and the full representation sent.
+    Invalid,
+    /// The header is valid but none of its ranges overlap the representation.
+    Unsatisfiable,
+    /// The ranges ask for more bytes than the representation has, which only
+    /// overlapping ranges can do; the header should be ignored and the full
+    /// representation sent.
+    Excessive,
+}
+
+pub fn parse_range(value: &str) -> Result<Vec<RangeSpec>, RangeError> {
+    let value = value.trim();
+    let unit = value.get(..BYTES_UNIT.len()).ok_or(RangeError::Invalid)?;
+    if !unit.eq_ignore_ascii_case(BYTES_UNIT) {
+        return Err(RangeError::Invalid);
+    }
+
+    let mut specs = Vec::new();
+    for part in value[BYTES_UNIT.len()..].split(',') {
+        let part = part.trim();
+        if part.is_empty() {
+            // Empty list elements are allowed by the list syntax.
+            continue;
+        }
+        specs.push(parse_range_spec(part)?);
+        if specs.len() > MAX_RANGES {
+            return Err(RangeError::Invalid);
+        }
+    }
+
+    if specs.is_empty() {
+        return Err(RangeError::Invalid);
+    }
+    Ok(specs)
+}
+
+fn parse_range_spec(spec: &str) -> Result<RangeSpec, RangeError> {
+    let (first, last) = spec.split_once('-').ok_or(RangeError::Invalid)?;
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        return Ok(RangeSpec::Suffix(parse_position(last)?));
+    }
+
+    let first = parse_position(first)?;
+    if last.is_empty() {
+        return Ok(RangeSpec::From(first));
+    }
+
+    let last = parse_position(last)?;
+    if last < first {
+        return Err(RangeError::Invalid);
+    }
+    Ok(RangeSpec::FromTo(first, last))
+}
+
+fn parse_position(position: &str) -> Result<u64, RangeError> {
+    if position.is_empty() || !position.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(RangeError::Invalid);
+    }
+    position.parse::<u64>().map_err(|_| RangeError::Invalid)
+}
+
+/// Parses a `Range` header and resolves it against a representation of `len`
+/// bytes, dropping ranges that fall entirely outside of it. The rest are
+/// sorted, and ranges that overlap or touch are merged into one.
+pub fn resolve_ranges(value: &str, len: u64) -> Result<Vec<ByteRange>, RangeError> {
+    let mut ranges: Vec<ByteRange> = parse_range(value)?
+        .iter()
+        .filter_map(|spec| spec.resolve(len))
+        .collect();
+
+    if ranges.is_empty() {
+        return Err(RangeError::Unsatisfiable);
+    }
+    let requested = ranges
+        .iter()
+        .fold(0u64, |sum, r| sum.saturating_add(r.len()));
+    if requested > len {
+        return Err(RangeError::Excessive);
+    }
+
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    Ok(merged)
+}
+
+/// Evaluates an `If-Range` header value against the current validators. The
+/// range is only honoured when the validator matches exactly; weak entity tags
+/// never match.
+pub fn if_range_matches(if_range: &str, validators: &Validators) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return match (if_range.parse::<EntityTag>(), &validators.etag) {
+            (Ok(tag), Some(etag)) => tag.strong_eq(etag),
+            _ => false,
+        };
+    }
+    match (if_range.parse::<HttpDate>(), validators.last_modified) {
+        (Ok(date), Some(last_modified)) => date == last_modified,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_single_range() {
+        assert_eq!(
+            parse_range("bytes=0-499"),
+            Ok(vec![RangeSpec::FromTo(0, 499)])
+        );
+    }
+
+    #[test]
+    fn test_parse_suffix_and_open_ended_ranges() {
+        assert_eq!(
+            parse_range("bytes=-500, 9500-"),
+            Ok(vec![RangeSpec::Suffix(500), RangeSpec::From(9500)])
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_ranges() {
+        assert_eq!(parse_range("items=0-1"), Err(RangeError::Invalid));
+        assert_eq!(parse_range("bytes=5-1"), Err(RangeError::Invalid));
+        assert_eq!(parse_range("bytes=a-b"), Err(RangeError::Invalid));
+        assert_eq!(parse_range("bytes=-"), Err(RangeError::Invalid));
+        assert_eq!(parse_range("bytes="), Err(RangeError::Invalid));
+    }
+
+    #[test]
+    fn test_resolve_ranges() {
+        let ranges = resolve_ranges("bytes=-2,0-0,5-6,20-", 10).unwrap();
+        assert_eq!(
+            ranges,
+            vec![
+                ByteRange { start: 0, end: 0 },
+                ByteRange { start: 5, end: 6 },
+                ByteRange { start: 8, end: 9 },
+            ]
+        );
+        assert_eq!(ranges[2].content_range(10), "bytes 8-9/10");
+    }
+
+    #[test]
+    fn test_overlapping_ranges_are_merged() {
+        assert_eq!(
+            resolve_ranges("bytes=6-7,0-2,2-3,4-4", 10),
+            Ok(vec![
+                ByteRange { start: 0, end: 4 },
+                ByteRange { start: 6, end: 7 },
+            ])
+        );
+        assert_eq!(
+            resolve_ranges("bytes=0-,0-,0-", 10),
+            Err(RangeError::Excessive)
+        );
+        assert_eq!(
+            resolve_ranges("bytes=0-5,-5", 10),
+            Err(RangeError::Excessive)
+        );
+    }
+
+    #[test]
+    fn test_resolve_unsatisfiable() {
+        assert_eq!(
+            resolve_ranges("bytes=10-20", 10),
+            Err(RangeError::Unsatisfiable)
+        );
+        assert_eq!(
+            resolve_ranges("bytes=-0", 10),
+            Err(RangeError::Unsatisfiable)
+        );
+    }
+
+    #[test]
+    fn test_if_range() {
+        let validators = Validators::new()
+            .etag(EntityTag::strong("abc"))
+            .last_modified(HttpDate::from_unix_secs(1_445_412_480));
+        assert!(if_range_matches("\"abc\"", &validators));
+        assert!(!if_range_matches("W/\"abc\"", &validators));
+        assert!(!if_range_matches("\"abd\"", &validators));
+        assert!(if_range_matches(
+            "Wed, 21 Oct 2015 07:28:00 GMT",
+            &validators
+        ));
+        assert!(!if_range_matches(
+            "Wed, 21 Oct 2015 07:28:01 GMT",
+            &validators
+        ));
+
+        let weak = Validators::new().etag(EntityTag::weak("abc"));
+        assert!(!if_range_matches("\"abc\"", &weak));
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::net::ConnectionInfo;
+use crate::request::Request;
+use crate::response::{Response, StatusCode};
+
+const DEFAULT_MAX_KEYS: usize = 10_000;
+
+type KeyFn = dyn Fn(&Request) -> Option<String> + Send + Sync;
+
+/// The quota left for the client that sent a request, added to its
+/// [`extensions`](crate::extensions::Extensions) by [`RateLimit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitStatus {
+    /// The most requests the client may make at once.
+    pub limit: u32,
+    /// How many more requests the client may make right now.
+    pub remaining: u32,
+    /// How long until the client's quota is full again.
+    pub reset: Duration,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // Tells apart buckets updated at the same instant in `recency`.
+    seq: u64,
+}
+
+struct Buckets {
+    map: HashMap<String, Bucket>,
+    // The keys in `map` by when they were last seen, stalest first.
+    recency: BTreeMap<(Instant, u64), String>,
+    next_seq: u64,
+}
+
+/// Per-client rate limiting with a token bucket for each client.
+///
+/// Each bucket holds up to `burst` tokens and refills at the configured
+/// rate; every request takes one, and a request that finds the bucket empty
+/// is answered with `429 Too Many Requests` and a `Retry-After` header.
+/// Responses carry `RateLimit-Limit`, `RateLimit-Remaining` and
+/// `RateLimit-Reset` headers.
+///
+/// Clients are told apart by their IP address unless a [`key`](Self::key)
+/// function says otherwise. Buckets that have refilled are dropped, and at
+/// most `max_keys` are kept, so memory stays bounded however many clients
+/// there are.
+pub struct RateLimit {
+    // Tokens per second.
+    rate: f64,
+    burst: u32,
+    max_keys: usize,
+    key: Arc<KeyFn>,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimit {
+    /// Allows `requests` requests every `per`, with bursts of up to
+    /// `requests`.
+    pub fn new(requests: u32, per: Duration) -> Self {
+        let requests = requests.max(1);
+        Self {
+            rate: requests as f64 / per.as_secs_f64().max(f64::MIN_POSITIVE),
+            burst: requests,
+            max_keys: DEFAULT_MAX_KEYS,
+            key: Arc::new(peer_ip),
+            buckets: Mutex::new(Buckets {
+                map: HashMap::new(),
+                recency: BTreeMap::new(),
+                next_seq: 0,
+            }),
+        }
+    }
+
+    /// How many requests a client may make at once after being idle.
+    pub fn burst(mut self, burst: u32) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+
+    /// Identifies the client a request counts against, for example by an
+    /// API key header. Requests for which `key` returns `None` are not
+    /// limited.
+    pub fn key(mut self, key: impl Fn(&Request) -> Option<String> + Send + Sync + 'static) -> Self {
+        self.key = Arc::new(key);
+        self
+    }
+
+    /// The most clients tracked at once. When a new client arrives at the
+    /// limit, the one seen least recently is forgotten.
+    pub fn max_keys(mut self, max_keys: usize) -> Self {
+        self.max_keys = max_keys.max(1);
+        self
+    }
+
+    /// Takes a token for the client that sent `request`, recording its
+    /// [`RateLimitStatus`] in the request's extensions, or answers `429 Too
+    /// Many Requests` if it has none left.
+    pub fn admit(&self, request: &mut Request) -> Option<Response> {
+        let key = (self.key)(request)?;
+        let (status, retry_after) = self.check_at(key, Instant::now());
+        request.extensions.insert(status);
+        let retry_after = retry_after?;
+        let mut response = Response::builder()
+            .status(StatusCode::TooManyRequests)
+            .header("retry-after", &seconds(retry_after).to_string())
+            .build();
+        set_headers(&mut response, &status);
+        Some(response)
+    }
+
+    /// Adds the `RateLimit-*` headers to the response for a request that
+    /// [`admit`](Self::admit) let through.
+    pub fn add_headers(&self, request: &Request, mut response: Response) -> Response {
+        if let Some(status) = request.extensions.get::<RateLimitStatus>() {
+            set_headers(&mut response, status);
+        }
+        response
+    }
+
+    /// Takes a token from `key`'s bucket at `now`, returning the bucket's
+    /// status and, when it was empty, how long until a token is available.
+    fn check_at(&self, key: String, now: Instant) -> (RateLimitStatus, Option<Duration>) {
+        let burst = self.burst as f64;
+        let mut guard = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let buckets = &mut *guard;
+        let known = buckets.map.contains_key(&key);
+        self.evict(buckets, now, !known);
+
+        let seq = buckets.next_seq;
+        buckets.next_seq += 1;
+        let bucket = buckets.map.entry(key.clone()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            seq,
+        });
+        buckets.recency.remove(&(bucket.updated, bucket.seq));
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(burst);
+        bucket.updated = now;
+        bucket.seq = seq;
+        buckets.recency.insert((now, seq), key);
+
+        let retry_after = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        };
+        let status = RateLimitStatus {
+            limit: self.burst,
+            remaining: bucket.tokens as u32,
+            reset: Duration::from_secs_f64((burst - bucket.tokens) / self.rate),
+        };
+        (status, retry_after)
+    }
+
+    // A bucket that has refilled is no different from a new one, so it can
+    // be dropped. The stalest buckets are dropped while they have refilled,
+    // and also when a new key is `inserting` into a full map, so each call
+    // only looks at the front of `recency`.
+    fn evict(&self, buckets: &mut Buckets, now: Instant, inserting: bool) {
+        let (burst, rate) = (self.burst as f64, self.rate);
+        while let Some(stalest) = buckets.recency.first_entry() {
+            let bucket = &buckets.map[stalest.get()];
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            let refilled = bucket.tokens + elapsed * rate >= burst;
+            let crowded = inserting && buckets.map.len() >= self.max_keys;
+            if !refilled && !crowded {
+                break;
+            }
+            buckets.map.remove(&stalest.remove());
+        }
+    }
+}
+
+/// The default key: the client's IP address, or `None` on a Unix socket.
+fn peer_ip(request: &Request) -> Option<String> {
+    let info = request.extensions.get::<ConnectionInfo>()?;
+    Some(info.peer_addr?.ip().to_string())
+}
+
+fn set_headers(response: &mut Response, status: &RateLimitStatus) {
+    let headers = &mut response.headers;
+    headers.set("ratelimit-limit", &status.limit.to_string());
+    headers.set("ratelimit-remaining", &status.remaining.to_string());
+    headers.set("ratelimit-reset", &seconds(status.reset).to_string());
+}
+
+// Whole seconds, rounded up so that waiting that long is always enough.
+fn seconds(duration: Duration) -> u64 {
+    let secs = duration.as_secs();
+    if duration.subsec_nanos() > 0 {
+        secs + 1
+    } else {
+        secs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from(ip: &str) -> Request {
+        let mut request = Request::new();
+        request.extensions.insert(ConnectionInfo {
+            peer_addr: Some(format!("{}:40000", ip).parse().unwrap()),
+            ..ConnectionInfo::default()
+        });
+        request
+    }
+
+    fn key(name: &str) -> String {
+        name.to_string()
+    }
+
+    #[test]
+    fn test_allows_burst_then_refills() {
+        let limit = RateLimit::new(2, Duration::from_secs(1)).burst(3);
+        let start = Instant::now();
+        for remaining in [2, 1, 0] {
+            let (status, retry_after) = limit.check_at(key("a"), start);
+            assert_eq!(status.remaining, remaining);
+            assert_eq!(retry_after, None);
+        }
+        let (status, retry_after) = limit.check_at(key("a"), start);
+        assert_eq!(status.remaining, 0);
+        assert_eq!(retry_after, Some(Duration::from_millis(500)));
+        assert_eq!(status.reset, Duration::from_millis(1500));
+
+        let (_, retry_after) = limit.check_at(key("a"), start + Duration::from_millis(500));
+        assert_eq!(retry_after, None);
+        // Other clients have buckets of their own.
+        let (status, _) = limit.check_at(key("b"), start);
+        assert_eq!(status.remaining, 2);
+    }
+
+    #[test]
+    fn test_answers_429_with_headers() {
+        let limit = RateLimit::new(1, Duration::from_secs(10));
+        let mut request = from("192.0.2.1");
+        assert!(limit.admit(&mut request).is_none());
+        let response = limit.add_headers(&request, Response::builder().build());
+        assert_eq!(response.headers.get("ratelimit-limit"), Some("1"));
+        assert_eq!(response.headers.get("ratelimit-remaining"), Some("0"));
+        assert_eq!(response.headers.get("ratelimit-reset"), Some("10"));
+
+        let response = limit.admit(&mut from("192.0.2.1")).unwrap();
+        assert_eq!(response.status, StatusCode::TooManyRequests);
+        assert_eq!(response.headers.get("retry-after"), Some("10"));
+        assert_eq!(response.headers.get("ratelimit-remaining"), Some("0"));
+        assert!(limit.admit(&mut from("192.0.2.2")).is_none());
+    }
+
+    #[test]
+    fn test_custom_key() {
+        let limit = RateLimit::new(1, Duration::from_secs(60))
+            .key(|request| request.headers.get("x-api-key").map(str::to_string));
+        let with_key = |value: &str| {
+            let mut request = Request::new();
+            request.headers.set("X-Api-Key", value);
+            request
+        };
+        assert!(limit.admit(&mut with_key("one")).is_none());
+        assert!(limit.admit(&mut with_key("one")).is_some());
+        assert!(limit.admit(&mut with_key("two")).is_none());
+        // Requests without a key are not limited.
+        for _ in 0..3 {
+            let mut request = Request::new();
+            assert!(limit.admit(&mut request).is_none());
+            assert!(request.extensions.get::<RateLimitStatus>().is_none());
+        }
+    }
+
+    #[test]
+    fn test_requests_without_peer_address_are_not_limited() {
+        let limit = RateLimit::new(1, Duration::from_secs(60));
+        for _ in 0..3 {
+            assert!(limit.admit(&mut Request::new()).is_none());
+        }
+    }
+
+    #[test]
+    fn test_drops_refilled_buckets() {
+        let limit = RateLimit::new(10, Duration::from_secs(1));
+        let start = Instant::now();
+        limit.check_at(key("a"), start);
+        limit.check_at(key("b"), start + Duration::from_millis(950));
+        // A second later `a` has refilled and is swept; `b` has not.
+        limit.check_at(key("c"), start + Duration::from_secs(1));
+        let buckets = limit.buckets.lock().unwrap();
+        let mut keys: Vec<_> = buckets.map.keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, ["b", "c"]);
+    }
+
+    #[test]
+    fn test_max_keys_forgets_least_recently_seen() {
+        let limit = RateLimit::new(1, Duration::from_secs(3600)).max_keys(2);
+        let start = Instant::now();
+        limit.check_at(key("a"), start);
+        limit.check_at(key("b"), start + Duration::from_secs(1));
+        // A client already tracked keeps its bucket, even at the limit.
+        let (_, retry_after) = limit.check_at(key("a"), start + Duration::from_secs(2));
+        assert!(retry_after.is_some());
+        limit.check_at(key("c"), start + Duration::from_secs(3));
+        let buckets = limit.buckets.lock().unwrap();
+        assert_eq!(buckets.map.len(), 2);
+        assert_eq!(buckets.recency.len(), 2);
+        assert!(buckets.map.contains_key("a"));
+        assert!(buckets.map.contains_key("c"));
+    }
+}
@@ -1,13 +1,18 @@
-use std::io::Error as IoError;
-use std::io::Read;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Error as IoError, ErrorKind, Read};
 use std::str::from_utf8;
+use std::time::{Duration, Instant};
 
+use crate::extensions::Extensions;
 use crate::headers::Headers;
+use crate::timeouts::{RequestTimeouts, SocketTimeouts};
 
-const BUFFER_SIZE: usize = 8;
+const BUFFER_SIZE: usize = 8 * 1024;
 const CONTENT_LENGTH: &str = "content-length";
+const TRANSFER_ENCODING: &str = "transfer-encoding";
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ParseState {
     Initialized,
     Done,
@@ -15,12 +20,22 @@ pub enum ParseState {
     RequestStateParsingBody,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Request {
     pub state: ParseState,
     pub request_line: Option<RequestLine>,
     pub headers: Headers,
     pub body: Vec<u8>,
+    /// Path parameters captured by the router, e.g. `id` for `/users/:id`.
+    pub params: HashMap<String, String>,
+    /// Typed values attached by middleware for the handlers behind it.
+    pub extensions: Extensions,
+}
+
+impl Default for Request {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Request {
@@ -30,9 +45,33 @@ impl Request {
             request_line: None,
             headers: Headers::new(),
             body: Vec::new(),
+            params: HashMap::new(),
+            extensions: Extensions::new(),
         }
     }
 
+    /// The request method, or `""` before the request line is parsed.
+    pub fn method(&self) -> &str {
+        self.request_line
+            .as_ref()
+            .map(|line| line.method.as_str())
+            .unwrap_or("")
+    }
+
+    /// The request target without its query string.
+    pub fn path(&self) -> &str {
+        let target = self
+            .request_line
+            .as_ref()
+            .map(|line| line.request_target.as_str())
+            .unwrap_or("");
+        target.split_once('?').map_or(target, |(path, _)| path)
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|v| v.as_str())
+    }
+
     pub fn parse(&mut self, data: &[u8]) -> Result<usize, RequestError> {
         match self.state {
             ParseState::Initialized => {
@@ -41,17 +80,18 @@ impl Request {
                     Some(r) => {
                         self.state = ParseState::RequestStateParsingHeaders;
                         self.request_line = Some(r);
-                        return Ok(num_bytes);
+                        Ok(num_bytes)
                     }
-                    None => return Ok(num_bytes),
+                    None => Ok(num_bytes),
                 }
             }
             ParseState::RequestStateParsingHeaders => {
                 let (consumed, is_done) = self.headers.parse(data)?;
                 if is_done {
+                    self.check_framing()?;
                     self.state = ParseState::RequestStateParsingBody
                 }
-                return Ok(consumed);
+                Ok(consumed)
             }
             ParseState::RequestStateParsingBody => match self.headers.get(CONTENT_LENGTH) {
                 Some(v) => {
@@ -78,65 +118,206 @@ impl Request {
                 }
                 None => {
                     self.state = ParseState::Done;
-                    return Ok(0);
+                    Ok(0)
                 }
             },
-            ParseState::Done => return Err(RequestError::DoneState),
+            ParseState::Done => Err(RequestError::DoneState),
         }
     }
 }
 
-pub fn request_from_reader<R: Read>(mut reader: R) -> Result<Request, RequestError> {
-    let mut request = Request::new();
-
-    let mut buffer = [0u8; BUFFER_SIZE];
-    let mut accumulator: Vec<u8> = Vec::new();
-
-    loop {
-        let n = match reader.read(&mut buffer) {
-            Ok(n) => n,
-            Err(_) => return Err(RequestError::InvalidRequest),
-        };
-
-        if n == 0 {
-            match request.state {
-                ParseState::Done => return Ok(request),
-                ParseState::Initialized
-                | ParseState::RequestStateParsingHeaders
-                | ParseState::RequestStateParsingBody => {
-                    return Err(RequestError::InvalidRequest);
+impl Request {
+    // Request bodies are only delimited by Content-Length. A body sent with
+    // a transfer coding cannot be skipped, so reading on would take its
+    // bytes for the next request; RFC 9112 section 6.3 has the connection
+    // closed instead.
+    fn check_framing(&self) -> Result<(), RequestError> {
+        match (
+            self.headers.get(TRANSFER_ENCODING),
+            self.headers.get(CONTENT_LENGTH),
+        ) {
+            (Some(_), Some(_)) => Err(RequestError::AmbiguousLength),
+            (Some(_), None) => Err(RequestError::UnsupportedTransferEncoding),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Reads requests one after another from a persistent connection. Bytes
+/// read past the end of one request, such as a pipelined request sent right
+/// behind it, are kept for the next call.
+pub struct RequestReader<R: Read> {
+    reader: R,
+    accumulator: Vec<u8>,
+}
+
+impl<R: Read> RequestReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            accumulator: Vec::new(),
+        }
+    }
+
+    /// The underlying reader, for writing responses back on the same stream.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Reads the next request. Returns `None` when the peer closes the
+    /// connection cleanly between requests; closing partway through a
+    /// request is an error.
+    pub fn next_request(&mut self) -> Result<Option<Request>, RequestError> {
+        self.read_next(None, |_, _| Ok(()))
+    }
+
+    fn read_next(
+        &mut self,
+        timeouts: Option<&RequestTimeouts>,
+        mut set_timeout: impl FnMut(&mut R, Option<Duration>) -> io::Result<()>,
+    ) -> Result<Option<Request>, RequestError> {
+        let mut request = Request::new();
+        let mut buffer = [0u8; BUFFER_SIZE];
+
+        let called_at = Instant::now();
+        // When the first byte of this request arrived. Leftover bytes from a
+        // pipelined request count as arriving straight away.
+        let mut first_byte_at = (!self.accumulator.is_empty()).then_some(called_at);
+        let mut body_started_at = None;
+
+        loop {
+            // Drain as much of the accumulator as the parser can use before
+            // asking the reader for more; a body-less request is complete as
+            // soon as the blank line after the headers arrives.
+            loop {
+                if let ParseState::Done = request.state {
+                    return Ok(Some(request));
+                }
+                let state_before = std::mem::discriminant(&request.state);
+                let consumed = request.parse(&self.accumulator)?;
+                if consumed > 0 {
+                    let _ = self.accumulator.drain(..consumed);
+                }
+                if consumed == 0 && std::mem::discriminant(&request.state) == state_before {
+                    break;
+                }
+            }
+
+            let timeout_error = match first_byte_at {
+                None => RequestError::IdleTimeout,
+                Some(_) => RequestError::Timeout,
+            };
+            if let Some(timeouts) = timeouts {
+                let deadline = match (&request.state, first_byte_at) {
+                    (ParseState::RequestStateParsingBody, _) => {
+                        let started = *body_started_at.get_or_insert_with(Instant::now);
+                        timeouts.body_deadline(started, request.body.len() as u64)
+                    }
+                    (_, None) => timeouts.idle.map(|idle| called_at + idle),
+                    (_, Some(at)) => timeouts.headers.map(|headers| at + headers),
+                };
+                let remaining = match deadline {
+                    Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                        Some(remaining) if !remaining.is_zero() => Some(remaining),
+                        _ => return Err(timeout_error),
+                    },
+                    None => None,
+                };
+                set_timeout(&mut self.reader, remaining)
+                    .map_err(|_| RequestError::InvalidRequest)?;
+            }
+
+            let n = match self.reader.read(&mut buffer) {
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Err(timeout_error);
+                }
+                Err(_) => return Err(RequestError::InvalidRequest),
+            };
+
+            if n == 0 {
+                let untouched = matches!(request.state, ParseState::Initialized);
+                if untouched && self.accumulator.is_empty() {
+                    return Ok(None);
                 }
+                return Err(RequestError::InvalidRequest);
             }
+
+            first_byte_at.get_or_insert_with(Instant::now);
+            self.accumulator.extend_from_slice(&buffer[..n]);
         }
+    }
+}
 
-        accumulator.extend_from_slice(&buffer[..n]);
-        match request.state {
-            ParseState::Initialized
-            | ParseState::RequestStateParsingHeaders
-            | ParseState::RequestStateParsingBody => {
-                let consumed = request.parse(&accumulator).unwrap();
-                if consumed > 0 {
-                    let _ = accumulator.drain(..consumed);
+impl<R: Read + SocketTimeouts> RequestReader<R> {
+    /// Like [`next_request`](Self::next_request), but gives up with
+    /// [`RequestError::IdleTimeout`] when no request starts in time and with
+    /// [`RequestError::Timeout`] when one starts but is not finished in time.
+    pub fn next_request_within(
+        &mut self,
+        timeouts: &RequestTimeouts,
+    ) -> Result<Option<Request>, RequestError> {
+        self.read_next(Some(timeouts), |reader, timeout| {
+            reader.set_read_timeout(timeout)
+        })
+    }
+
+    /// Waits for the first byte of the next request, giving up with
+    /// [`RequestError::IdleTimeout`] when none arrives in time. Returns
+    /// `false` when the peer closes the connection first. Lets a server tell
+    /// a connection that is between requests from one that is sending one.
+    pub fn wait_for_request_within(
+        &mut self,
+        timeouts: &RequestTimeouts,
+    ) -> Result<bool, RequestError> {
+        if !self.accumulator.is_empty() {
+            return Ok(true);
+        }
+        self.reader
+            .set_read_timeout(timeouts.idle)
+            .map_err(|_| RequestError::InvalidRequest)?;
+        let mut buffer = [0u8; BUFFER_SIZE];
+        loop {
+            match self.reader.read(&mut buffer) {
+                Ok(0) => return Ok(false),
+                Ok(n) => {
+                    self.accumulator.extend_from_slice(&buffer[..n]);
+                    return Ok(true);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Err(RequestError::IdleTimeout);
                 }
+                Err(_) => return Err(RequestError::InvalidRequest),
             }
-            ParseState::Done => return Ok(request),
         }
     }
 }
 
+pub fn request_from_reader<R: Read>(reader: R) -> Result<Request, RequestError> {
+    RequestReader::new(reader)
+        .next_request()?
+        .ok_or(RequestError::InvalidRequest)
+}
+
 pub fn parse_request_line(
     request_string: &[u8],
 ) -> Result<(Option<RequestLine>, usize), RequestError> {
     if let Some(pos) = request_string.windows(2).position(|w| w == b"\r\n") {
-        let line = from_utf8(&request_string[..pos]).unwrap();
+        let line =
+            from_utf8(&request_string[..pos]).map_err(|_| RequestError::InvalidRequestLine)?;
         let request_string_parts: Vec<&str> = line.split_ascii_whitespace().collect();
-        // let request_line = parse_request_line(line).map_err(|e| e)?;
         if request_string_parts.len() < 3 {
             return Err(RequestError::InvalidRequestLine);
         }
-        let method = validate_request_method(request_string_parts[0]).map_err(|e| e)?;
-        let request_target = validate_target(request_string_parts[1]).map_err(|e| e)?;
-        let http_version = validate_http_version(request_string_parts[2]).map_err(|e| e)?;
+        let method = validate_request_method(request_string_parts[0])?;
+        let request_target = validate_target(request_string_parts[1])?;
+        let http_version = validate_http_version(request_string_parts[2])?;
         return Ok((
             Some(RequestLine {
                 method,
@@ -160,7 +341,7 @@ fn validate_request_method(method: &str) -> Result<String, RequestError> {
 fn validate_http_version(http_version: &str) -> Result<String, RequestError> {
     let http_parts: Vec<&str> = http_version.split('/').collect();
     let http_version = http_parts.into_iter().last().unwrap();
-    let is_version_one = http_version.eq("1.1");
+    let is_version_one = http_version.eq("1.1") || http_version.eq("1.0");
     if !is_version_one {
         return Err(RequestError::InvalidRequestHttpVersion);
     }
@@ -174,11 +355,63 @@ fn validate_target(target: &str) -> Result<String, RequestError> {
     Ok(target.to_string())
 }
 
-pub fn parse_body(data: &[u8], body_length: u32) -> usize {
-    unimplemented!()
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+    Other(String),
 }
 
-#[derive(Debug)]
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+            Method::Other(method) => method,
+        }
+    }
+}
+
+impl From<&str> for Method {
+    /// Methods are case-sensitive, so `get` is an unknown method rather than
+    /// `GET`.
+    fn from(method: &str) -> Self {
+        match method {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "CONNECT" => Method::Connect,
+            "OPTIONS" => Method::Options,
+            "TRACE" => Method::Trace,
+            "PATCH" => Method::Patch,
+            other => Method::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct RequestLine {
     pub method: String,
     pub request_target: String,
@@ -275,6 +508,69 @@ fn test_invalid_request_line_not_enough_parts() {
     assert!(matches!(err, RequestError::InvalidRequestLine));
 }
 
+#[test]
+fn test_http_1_0_request_line() {
+    let chunk_reader = ChunkReader {
+        data: b"GET / HTTP/1.0\r\n\r\n".to_vec(),
+        num_bytes_per_read: 5,
+        pos: 0,
+    };
+
+    let r = request_from_reader(chunk_reader).expect("Expected HTTP/1.0 to be accepted");
+    assert_eq!(r.request_line.unwrap().http_version, "1.0");
+}
+
+#[test]
+fn test_pipelined_requests_are_read_in_order() {
+    let chunk_reader = ChunkReader {
+        data: b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /b HTTP/1.1\r\n\r\n".to_vec(),
+        num_bytes_per_read: 64,
+        pos: 0,
+    };
+    let mut requests = RequestReader::new(chunk_reader);
+
+    let first = requests.next_request().unwrap().unwrap();
+    assert_eq!(first.path(), "/a");
+    assert_eq!(first.body, b"hello");
+
+    let second = requests.next_request().unwrap().unwrap();
+    assert_eq!(second.path(), "/b");
+
+    assert!(requests.next_request().unwrap().is_none());
+}
+
+#[test]
+fn test_eof_inside_request_is_an_error() {
+    let chunk_reader = ChunkReader {
+        data: b"GET / HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nHost: loc".to_vec(),
+        num_bytes_per_read: 7,
+        pos: 0,
+    };
+    let mut requests = RequestReader::new(chunk_reader);
+
+    assert!(requests.next_request().unwrap().is_some());
+    assert!(matches!(
+        requests.next_request(),
+        Err(RequestError::InvalidRequest)
+    ));
+}
+
+#[test]
+fn test_transfer_coded_bodies_are_refused() {
+    let request = |head: &str| {
+        let raw = format!("POST / HTTP/1.1\r\n{}\r\n5\r\nhello\r\n0\r\n\r\n", head);
+        request_from_reader(raw.as_bytes())
+    };
+    assert!(matches!(
+        request("Transfer-Encoding: chunked\r\n"),
+        Err(RequestError::UnsupportedTransferEncoding)
+    ));
+    assert!(matches!(
+        request("Transfer-Encoding: chunked\r\nContent-Length: 5\r\n"),
+        Err(RequestError::AmbiguousLength)
+    ));
+}
+
 #[derive(Debug)]
 pub enum RequestError {
     InvalidRequest,
@@ -285,4 +581,14 @@ pub enum RequestError {
     InvalidHeader,
     DoneState,
     InvalidBody,
+    /// A request started but did not finish within its time limit.
+    Timeout,
+    /// No request started within the idle time limit.
+    IdleTimeout,
+    /// The request body uses a transfer coding, which this server does not
+    /// decode.
+    UnsupportedTransferEncoding,
+    /// The request has both `Transfer-Encoding` and `Content-Length`, so its
+    /// length cannot be trusted.
+    AmbiguousLength,
 }
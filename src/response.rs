@@ -1,53 +1,302 @@
-use std::io::{Write, Result};
+use std::borrow::Cow;
+use std::io::{Read, Write, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::body::Body;
+use crate::conditional::{Precondition, Validators, evaluate_preconditions};
+use crate::date::date_header;
 use crate::headers::Headers;
+use crate::range::{ByteRange, RangeError, if_range_matches, resolve_ranges};
+use crate::writer::{ResponseWriter, WriterError, is_chunked};
 
-pub enum StatusCode {
-    OK = 200,
-    BadRequest = 400,
-    InternalServerError = 500,
-}
+macro_rules! status_codes {
+    ($($variant:ident = $code:literal, $reason:literal;)+) => {
+        /// A response status. Every status in the IANA HTTP Status Code
+        /// Registry has a variant; anything else in `100..=999` can be sent
+        /// as `Custom`, which only [`StatusCode::from_u16`] and
+        /// [`StatusCode::custom`] can build.
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum StatusCode {
+            $($variant,)+
+            Custom(CustomStatus),
+        }
 
+        impl StatusCode {
+            pub fn code(&self) -> u16 {
+                match self {
+                    $(StatusCode::$variant => $code,)+
+                    StatusCode::Custom(custom) => custom.code,
+                }
+            }
 
-pub fn write_status_line<W: Write>(writer: &mut W, status_code: StatusCode) -> Result<()>{
+            /// The registered reason phrase for `code`, if it has one.
+            pub fn canonical_reason(code: u16) -> Option<&'static str> {
+                match code {
+                    $($code => Some($reason),)+
+                    _ => None,
+                }
+            }
 
-    let reason = match status_code {
-        StatusCode::OK => "Ok", 
-        StatusCode::BadRequest => "Bad Request",
-        StatusCode::InternalServerError => "Internal Server Error"
+            fn registered(code: u16) -> Option<Self> {
+                match code {
+                    $($code => Some(StatusCode::$variant),)+
+                    _ => None,
+                }
+            }
+        }
     };
+}
+
+status_codes! {
+    Continue = 100, "Continue";
+    SwitchingProtocols = 101, "Switching Protocols";
+    Processing = 102, "Processing";
+    EarlyHints = 103, "Early Hints";
+    OK = 200, "OK";
+    Created = 201, "Created";
+    Accepted = 202, "Accepted";
+    NonAuthoritativeInformation = 203, "Non-Authoritative Information";
+    NoContent = 204, "No Content";
+    ResetContent = 205, "Reset Content";
+    PartialContent = 206, "Partial Content";
+    MultiStatus = 207, "Multi-Status";
+    AlreadyReported = 208, "Already Reported";
+    IMUsed = 226, "IM Used";
+    MultipleChoices = 300, "Multiple Choices";
+    MovedPermanently = 301, "Moved Permanently";
+    Found = 302, "Found";
+    SeeOther = 303, "See Other";
+    NotModified = 304, "Not Modified";
+    UseProxy = 305, "Use Proxy";
+    TemporaryRedirect = 307, "Temporary Redirect";
+    PermanentRedirect = 308, "Permanent Redirect";
+    BadRequest = 400, "Bad Request";
+    Unauthorized = 401, "Unauthorized";
+    PaymentRequired = 402, "Payment Required";
+    Forbidden = 403, "Forbidden";
+    NotFound = 404, "Not Found";
+    MethodNotAllowed = 405, "Method Not Allowed";
+    NotAcceptable = 406, "Not Acceptable";
+    ProxyAuthenticationRequired = 407, "Proxy Authentication Required";
+    RequestTimeout = 408, "Request Timeout";
+    Conflict = 409, "Conflict";
+    Gone = 410, "Gone";
+    LengthRequired = 411, "Length Required";
+    PreconditionFailed = 412, "Precondition Failed";
+    ContentTooLarge = 413, "Content Too Large";
+    URITooLong = 414, "URI Too Long";
+    UnsupportedMediaType = 415, "Unsupported Media Type";
+    RangeNotSatisfiable = 416, "Range Not Satisfiable";
+    ExpectationFailed = 417, "Expectation Failed";
+    MisdirectedRequest = 421, "Misdirected Request";
+    UnprocessableContent = 422, "Unprocessable Content";
+    Locked = 423, "Locked";
+    FailedDependency = 424, "Failed Dependency";
+    TooEarly = 425, "Too Early";
+    UpgradeRequired = 426, "Upgrade Required";
+    PreconditionRequired = 428, "Precondition Required";
+    TooManyRequests = 429, "Too Many Requests";
+    RequestHeaderFieldsTooLarge = 431, "Request Header Fields Too Large";
+    UnavailableForLegalReasons = 451, "Unavailable For Legal Reasons";
+    InternalServerError = 500, "Internal Server Error";
+    NotImplemented = 501, "Not Implemented";
+    BadGateway = 502, "Bad Gateway";
+    ServiceUnavailable = 503, "Service Unavailable";
+    GatewayTimeout = 504, "Gateway Timeout";
+    HTTPVersionNotSupported = 505, "HTTP Version Not Supported";
+    VariantAlsoNegotiates = 506, "Variant Also Negotiates";
+    InsufficientStorage = 507, "Insufficient Storage";
+    LoopDetected = 508, "Loop Detected";
+    NotExtended = 510, "Not Extended";
+    NetworkAuthenticationRequired = 511, "Network Authentication Required";
+}
+
+/// An unregistered status code, or a registered one with its own reason
+/// phrase. The fields are private so that every instance has been
+/// validated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CustomStatus {
+    code: u16,
+    reason: Option<String>,
+}
+
+impl CustomStatus {
+    pub fn code(&self) -> u16 {
+        self.code
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidStatusCode;
+
+impl StatusCode {
+    /// The registered status for `code`, or a `Custom` one without a reason
+    /// phrase for any other three digit code.
+    pub fn from_u16(code: u16) -> std::result::Result<Self, InvalidStatusCode> {
+        if !(100..=999).contains(&code) {
+            return Err(InvalidStatusCode);
+        }
+        Ok(Self::registered(code)
+            .unwrap_or(StatusCode::Custom(CustomStatus { code, reason: None })))
+    }
+
+    /// A status with a caller-chosen reason phrase, which may also be used to
+    /// reword a registered code.
+    pub fn custom(code: u16, reason: &str) -> std::result::Result<Self, InvalidStatusCode> {
+        let is_valid_reason = reason
+            .bytes()
+            .all(|b| b == b'\t' || b == b' ' || (0x21..=0x7e).contains(&b) || b >= 0x80);
+        if !(100..=999).contains(&code) || !is_valid_reason {
+            return Err(InvalidStatusCode);
+        }
+        Ok(StatusCode::Custom(CustomStatus {
+            code,
+            reason: Some(reason.to_string()),
+        }))
+    }
+
+    pub fn reason(&self) -> &str {
+        match self {
+            StatusCode::Custom(CustomStatus {
+                reason: Some(reason),
+                ..
+            }) => reason,
+            _ => Self::canonical_reason(self.code()).unwrap_or(""),
+        }
+    }
+
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.code())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.code())
+    }
+
+    pub fn is_redirect(&self) -> bool {
+        (300..400).contains(&self.code())
+    }
+
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.code())
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        (500..600).contains(&self.code())
+    }
+
+    /// `1xx`, `204` and `304` responses never have content (RFC 9110
+    /// section 6.4.1), so neither a body nor `content-length` is sent.
+    pub fn allows_body(&self) -> bool {
+        let code = self.code();
+        !(self.is_informational() || code == 204 || code == 304)
+    }
+}
+
 
-    write!(writer, "HTTP/1.1 {} {}\r\n", status_code as u16, reason)?;
+pub fn write_status_line<W: Write>(writer: &mut W, status_code: &StatusCode) -> Result<()>{
+    write!(writer, "HTTP/1.1 {} {}\r\n", status_code.code(), status_code.reason())?;
     Ok(())
 }
 
 pub fn get_default_headers(content_length: usize) -> Headers {
     let mut headers = Headers::new();
-    
-    headers.map.insert(
-        "content-length".to_string(), 
-        content_length.to_string()
-    );
-    
-    headers.map.insert(
-        "connection".to_string(), 
-        "close".to_string()
-    );
-    
-    headers.map.insert(
-        "content-type".to_string(), 
-        "text/plain".to_string()
-    );
-    
+
+    headers.set("content-length", &content_length.to_string());
+    DefaultHeaders::new().fill(&mut headers, true);
+
     headers
 }
 
+/// Headers the server adds to every response. A `date` header is always
+/// included and a `server` header when a token is configured; any header the
+/// handler has already set takes precedence over a default.
+#[derive(Debug, Clone)]
+pub struct DefaultHeaders {
+    headers: Headers,
+    server: Option<String>,
+}
+
+impl Default for DefaultHeaders {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DefaultHeaders {
+    /// The stock defaults: `content-type: text/plain`.
+    pub fn new() -> Self {
+        let mut headers = Headers::new();
+        headers.set("content-type", "text/plain");
+        Self {
+            headers,
+            server: None,
+        }
+    }
+
+    /// Defaults with nothing but the `date` header.
+    pub fn empty() -> Self {
+        Self {
+            headers: Headers::new(),
+            server: None,
+        }
+    }
+
+    /// Adds or replaces a default header.
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        self.headers.set(key, value);
+        self
+    }
+
+    pub fn remove_header(mut self, key: &str) -> Self {
+        self.headers.map.remove(&key.to_lowercase());
+        self
+    }
+
+    /// Sends `server: <token>` on every response.
+    pub fn server(mut self, token: &str) -> Self {
+        self.server = Some(token.to_string());
+        self
+    }
+
+    /// Fills in every default that the response does not already have. A
+    /// default `content-type` is left off statuses that carry no content.
+    pub fn apply(&self, response: &mut Response) {
+        let allows_body = response.status.allows_body();
+        self.fill(&mut response.headers, allows_body);
+    }
+
+    fn fill(&self, headers: &mut Headers, allows_body: bool) {
+        for (key, value) in &self.headers.map {
+            if key == "content-type" && !allows_body {
+                continue;
+            }
+            headers.map.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+        if let Some(server) = &self.server {
+            headers
+                .map
+                .entry("server".to_string())
+                .or_insert_with(|| server.clone());
+        }
+        headers
+            .map
+            .entry("date".to_string())
+            .or_insert_with(date_header);
+    }
+}
+
 pub fn write_headers<W: Write>(
     writer: &mut W, 
     headers: &Headers
 ) -> Result<()> {
     // Write each header as "Key: Value\r\n"
     for (key, value) in &headers.map {
-        write!(writer, "{}: {}\r\n", key, value)?;
+        write!(writer, "{}: {}\r\n", single_line(key), single_line(value))?;
     }
     
     // Write empty line to mark end of headers
@@ -55,3 +304,638 @@ pub fn write_headers<W: Write>(
     
     Ok(())
 }
+
+/// `text` with any CR or LF replaced by a space. Header names and values
+/// may be built from request data, and a line break in one would let the
+/// client add headers of its own or split the response.
+fn single_line(text: &str) -> Cow<'_, str> {
+    if text.contains(['\r', '\n']) {
+        Cow::Owned(text.replace(['\r', '\n'], " "))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn builder() -> ResponseBuilder {
+        ResponseBuilder::new()
+    }
+
+    /// Writes the status line, headers and body. `content-length` is set
+    /// from the body when its length is known; a streamed body of unknown
+    /// length is sent chunked, or to an HTTP/1.0 client delimited by closing
+    /// the connection. Statuses that do not allow a body get neither.
+    pub fn write_to<W: Write>(self, writer: &mut W) -> std::result::Result<(), WriterError> {
+        self.write_with(&mut ResponseWriter::new(writer))
+    }
+
+    /// Like [`Response::write_to`], for a connection already wrapped in a
+    /// [`ResponseWriter`] that has not started a response yet.
+    pub fn write_with<W: Write>(
+        self,
+        writer: &mut ResponseWriter<W>,
+    ) -> std::result::Result<(), WriterError> {
+        self.write_parts(writer, true)
+    }
+
+    /// Like [`Response::write_with`], but leaves the body off, as the answer
+    /// to a `HEAD` request must. The headers still describe the body, with
+    /// its `content-length` or chunked coding.
+    pub fn write_head_with<W: Write>(
+        self,
+        writer: &mut ResponseWriter<W>,
+    ) -> std::result::Result<(), WriterError> {
+        self.write_parts(writer, false)
+    }
+
+    fn write_parts<W: Write>(
+        mut self,
+        writer: &mut ResponseWriter<W>,
+        with_body: bool,
+    ) -> std::result::Result<(), WriterError> {
+        if !self.status.allows_body() {
+            self.headers.map.remove("content-length");
+            writer.write_status_line(&self.status)?;
+            writer.write_headers(&self.headers)?;
+            return writer.finish();
+        }
+
+        let can_chunk = writer.http_version() != "1.0";
+        if !can_chunk {
+            self.headers.map.remove("transfer-encoding");
+            self.headers.map.remove("trailer");
+        }
+        let len = match self.body.len() {
+            Some(len) if !is_chunked(&self.headers) => {
+                self.headers.set("content-length", &len.to_string());
+                Some(len)
+            }
+            len => {
+                self.headers.map.remove("content-length");
+                if can_chunk {
+                    self.headers.set("transfer-encoding", "chunked");
+                } else {
+                    // Without chunked coding the end of the body can only be
+                    // signalled by closing the connection.
+                    self.headers.set("connection", "close");
+                }
+                len
+            }
+        };
+
+        writer.write_status_line(&self.status)?;
+        writer.write_headers(&self.headers)?;
+        if !with_body {
+            return writer.finish_without_body();
+        }
+        match self.body {
+            Body::Bytes(bytes) => {
+                writer.write_body(&bytes)?;
+            }
+            Body::Reader { mut reader, .. } => {
+                Body::copy_reader(reader.as_mut(), len, writer)?;
+            }
+        }
+        writer.finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct ResponseBuilder {
+    status: StatusCode,
+    headers: Headers,
+    body: Body,
+}
+
+impl Default for ResponseBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResponseBuilder {
+    /// Starts a `200 OK` response with no headers and no body. The server
+    /// fills in its [`DefaultHeaders`] when the response is written.
+    pub fn new() -> Self {
+        Self {
+            status: StatusCode::OK,
+            headers: Headers::new(),
+            body: Body::empty(),
+        }
+    }
+
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Sets a header, replacing any default or earlier value for `key`.
+    /// Line breaks in `key` or `value` are sent as spaces.
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        self.headers.set(key, value);
+        self
+    }
+
+    /// Removes a header, including one of the defaults.
+    pub fn remove_header(mut self, key: &str) -> Self {
+        self.headers.map.remove(&key.to_lowercase());
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Body>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Streams the body from `reader` while the response is written. Pass
+    /// `len` when the size is known so `content-length` can be sent.
+    pub fn body_reader(mut self, reader: impl Read + Send + 'static, len: Option<u64>) -> Self {
+        self.body = Body::from_reader(reader, len);
+        self
+    }
+
+    pub fn build(self) -> Response {
+        Response {
+            status: self.status,
+            headers: self.headers,
+            body: self.body,
+        }
+    }
+}
+
+/// Builds the response for `body` given the request's headers, honouring
+/// `Range` and `If-Range`. A missing, malformed or stale range, or ranges
+/// that overlap enough to ask for more than the whole body, get the full
+/// body with `200`, a single range gets `206`, several ranges get a
+/// `multipart/byteranges` `206`, and ranges that miss the body entirely get
+/// `416`.
+pub fn range_response(
+    request_headers: &Headers,
+    content_type: &str,
+    body: &[u8],
+    validators: &Validators,
+) -> Response {
+    let complete_len = body.len() as u64;
+
+    let range = match request_headers.get("range") {
+        Some(range) => match request_headers.get("if-range") {
+            Some(if_range) if !if_range_matches(if_range, validators) => None,
+            _ => Some(range),
+        },
+        None => None,
+    };
+
+    let ranges = match range.map(|r| resolve_ranges(r, complete_len)) {
+        None | Some(Err(RangeError::Invalid | RangeError::Excessive)) => {
+            return representation(content_type, validators).body(body).build();
+        }
+        Some(Err(RangeError::Unsatisfiable)) => {
+            return representation(content_type, validators)
+                .status(StatusCode::RangeNotSatisfiable)
+                .remove_header("content-type")
+                .header("content-range", &format!("bytes */{}", complete_len))
+                .build();
+        }
+        Some(Ok(ranges)) => ranges,
+    };
+
+    if let [range] = ranges.as_slice() {
+        return representation(content_type, validators)
+            .status(StatusCode::PartialContent)
+            .header("content-range", &range.content_range(complete_len))
+            .body(range_slice(body, range))
+            .build();
+    }
+
+    let boundary = multipart_boundary();
+    let mut multipart = Vec::new();
+    for range in &ranges {
+        multipart.extend_from_slice(
+            format!(
+                "--{}\r\ncontent-type: {}\r\ncontent-range: {}\r\n\r\n",
+                boundary,
+                content_type,
+                range.content_range(complete_len)
+            )
+            .as_bytes(),
+        );
+        multipart.extend_from_slice(range_slice(body, range));
+        multipart.extend_from_slice(b"\r\n");
+    }
+    multipart.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+    representation(
+        &format!("multipart/byteranges; boundary={}", boundary),
+        validators,
+    )
+    .status(StatusCode::PartialContent)
+    .body(multipart)
+    .build()
+}
+
+/// Evaluates the request's preconditions against `validators` and returns
+/// `304 Not Modified` or `412 Precondition Failed` when one fails. Otherwise
+/// the response is built as by [`range_response`].
+pub fn conditional_response(
+    method: &str,
+    request_headers: &Headers,
+    content_type: &str,
+    body: &[u8],
+    validators: &Validators,
+) -> Response {
+    match evaluate_preconditions(method, request_headers, validators) {
+        Precondition::Proceed => range_response(request_headers, content_type, body, validators),
+        Precondition::NotModified => {
+            // A 304 carries the validators but no content.
+            let mut response = Response::builder()
+                .status(StatusCode::NotModified)
+                .build();
+            validators.write_headers(&mut response.headers);
+            response
+        }
+        Precondition::PreconditionFailed => Response::builder()
+            .status(StatusCode::PreconditionFailed)
+            .build(),
+    }
+}
+
+fn representation(content_type: &str, validators: &Validators) -> ResponseBuilder {
+    let mut headers = Headers::new();
+    headers.set("content-type", content_type);
+    headers.set("accept-ranges", "bytes");
+    validators.write_headers(&mut headers);
+    ResponseBuilder {
+        headers,
+        ..ResponseBuilder::new()
+    }
+}
+
+fn range_slice<'a>(body: &'a [u8], range: &ByteRange) -> &'a [u8] {
+    &body[range.start as usize..=range.end as usize]
+}
+
+fn multipart_boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:016x}{:08x}", nanos, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conditional::EntityTag;
+    use crate::date::HttpDate;
+    use std::io::Cursor;
+
+    const BODY: &[u8] = b"0123456789";
+
+    fn render(response: Response) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn ranged(range: Option<&str>, if_range: Option<&str>) -> String {
+        let mut request_headers = Headers::new();
+        if let Some(range) = range {
+            request_headers.set("Range", range);
+        }
+        if let Some(if_range) = if_range {
+            request_headers.set("If-Range", if_range);
+        }
+        let validators = Validators::new().etag(EntityTag::strong("v1"));
+        render(range_response(
+            &request_headers,
+            "text/plain",
+            BODY,
+            &validators,
+        ))
+    }
+
+    #[test]
+    fn test_builder_sets_content_length() {
+        let response = render(
+            Response::builder()
+                .status(StatusCode::BadRequest)
+                .header("Content-Type", "application/json")
+                .body("{}")
+                .build(),
+        );
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.contains("content-type: application/json\r\n"));
+        assert!(response.contains("content-length: 2\r\n"));
+        assert!(response.ends_with("\r\n\r\n{}"));
+    }
+
+    #[test]
+    fn test_line_breaks_cannot_split_headers() {
+        let response = render(
+            Response::builder()
+                .header("location", "/next\r\nset-cookie: session=stolen")
+                .header("x-echo\n", "a\rb")
+                .build(),
+        );
+        assert!(response.contains("location: /next  set-cookie: session=stolen\r\n"));
+        assert!(response.contains("x-echo : a b\r\n"));
+        assert!(!response.contains("\r\nset-cookie"));
+    }
+
+    #[test]
+    fn test_head_leaves_body_off() {
+        let head = |response: Response| {
+            let mut out = Vec::new();
+            response
+                .write_head_with(&mut ResponseWriter::new(&mut out))
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let response = head(Response::builder().body("hello").build());
+        assert!(response.contains("content-length: 5\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+
+        let response = head(
+            Response::builder()
+                .body_reader(Cursor::new(b"hello".to_vec()), None)
+                .build(),
+        );
+        assert!(response.contains("transfer-encoding: chunked\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_no_range_sends_full_body() {
+        let response = ranged(None, None);
+        assert!(response.starts_with("HTTP/1.1 200 "));
+        assert!(response.contains("accept-ranges: bytes\r\n"));
+        assert!(response.ends_with("\r\n\r\n0123456789"));
+    }
+
+    #[test]
+    fn test_single_range() {
+        let response = ranged(Some("bytes=2-4"), None);
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(response.contains("content-range: bytes 2-4/10\r\n"));
+        assert!(response.contains("content-length: 3\r\n"));
+        assert!(response.ends_with("\r\n\r\n234"));
+    }
+
+    #[test]
+    fn test_multiple_ranges() {
+        let response = ranged(Some("bytes=0-1,-2"), None);
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        let boundary = response
+            .split("boundary=")
+            .nth(1)
+            .and_then(|rest| rest.split("\r\n").next())
+            .unwrap();
+        assert!(response.contains(&format!(
+            "--{}\r\ncontent-type: text/plain\r\ncontent-range: bytes 0-1/10\r\n\r\n01\r\n",
+            boundary
+        )));
+        assert!(response.contains("content-range: bytes 8-9/10\r\n\r\n89\r\n"));
+        assert!(response.ends_with(&format!("--{}--\r\n", boundary)));
+    }
+
+    #[test]
+    fn test_overlapping_ranges() {
+        let response = ranged(Some("bytes=0-3,2-5"), None);
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(response.contains("content-range: bytes 0-5/10\r\n"));
+        assert!(response.ends_with("\r\n\r\n012345"));
+
+        let repeated = vec!["0-"; 100].join(",");
+        let response = ranged(Some(&format!("bytes={}", repeated)), None);
+        assert!(response.starts_with("HTTP/1.1 200 "));
+        assert!(response.ends_with("\r\n\r\n0123456789"));
+    }
+
+    #[test]
+    fn test_unsatisfiable_range() {
+        let response = ranged(Some("bytes=20-30"), None);
+        assert!(response.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"));
+        assert!(response.contains("content-range: bytes */10\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_stale_if_range_sends_full_body() {
+        let response = ranged(Some("bytes=2-4"), Some("\"v0\""));
+        assert!(response.starts_with("HTTP/1.1 200 "));
+        let response = ranged(Some("bytes=2-4"), Some("\"v1\""));
+        assert!(response.starts_with("HTTP/1.1 206 "));
+    }
+
+    #[test]
+    fn test_conditional_not_modified() {
+        let mut request_headers = Headers::new();
+        request_headers.set("If-None-Match", "\"v1\"");
+        let validators = Validators::new()
+            .etag(EntityTag::strong("v1"))
+            .last_modified(HttpDate::from_unix_secs(784_111_777));
+
+        let response = render(conditional_response(
+            "GET",
+            &request_headers,
+            "text/plain",
+            BODY,
+            &validators,
+        ));
+        assert!(response.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        assert!(response.contains("etag: \"v1\"\r\n"));
+        assert!(response.contains("last-modified: Sun, 06 Nov 1994 08:49:37 GMT\r\n"));
+        assert!(!response.contains("content-length"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_conditional_precondition_failed() {
+        let mut request_headers = Headers::new();
+        request_headers.set("If-Match", "\"v2\"");
+        let validators = Validators::new().etag(EntityTag::strong("v1"));
+
+        let response = render(conditional_response(
+            "PUT",
+            &request_headers,
+            "text/plain",
+            BODY,
+            &validators,
+        ));
+        assert!(response.starts_with("HTTP/1.1 412 Precondition Failed\r\n"));
+    }
+
+    #[test]
+    fn test_status_line_uses_canonical_reason() {
+        let mut out = Vec::new();
+        write_status_line(&mut out, &StatusCode::OK).unwrap();
+        assert_eq!(out, b"HTTP/1.1 200 OK\r\n");
+
+        let mut out = Vec::new();
+        write_status_line(&mut out, &StatusCode::MethodNotAllowed).unwrap();
+        assert_eq!(out, b"HTTP/1.1 405 Method Not Allowed\r\n");
+    }
+
+    #[test]
+    fn test_custom_status_codes() {
+        assert_eq!(StatusCode::from_u16(404), Ok(StatusCode::NotFound));
+        let unassigned = StatusCode::from_u16(599).unwrap();
+        assert!(matches!(&unassigned, StatusCode::Custom(custom) if custom.code() == 599));
+        assert_eq!(unassigned.reason(), "");
+        assert_eq!(StatusCode::from_u16(99), Err(InvalidStatusCode));
+        assert_eq!(StatusCode::from_u16(1000), Err(InvalidStatusCode));
+
+        let teapot = StatusCode::custom(418, "I'm a teapot").unwrap();
+        assert_eq!(teapot.code(), 418);
+        assert_eq!(teapot.reason(), "I'm a teapot");
+        assert!(teapot.is_client_error());
+        assert!(StatusCode::custom(200, "bad\r\nheader").is_err());
+    }
+
+    #[test]
+    fn test_status_classes() {
+        assert!(StatusCode::Continue.is_informational());
+        assert!(StatusCode::Created.is_success());
+        assert!(StatusCode::MovedPermanently.is_redirect());
+        assert!(StatusCode::NotFound.is_client_error());
+        assert!(StatusCode::ServiceUnavailable.is_server_error());
+        assert!(!StatusCode::from_u16(700).unwrap().is_server_error());
+    }
+
+    #[test]
+    fn test_no_content_statuses_drop_body() {
+        let response = render(
+            Response::builder()
+                .status(StatusCode::NoContent)
+                .body("ignored")
+                .build(),
+        );
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(!response.contains("content-length"));
+        assert!(response.ends_with("\r\n\r\n"));
+        assert!(!StatusCode::SwitchingProtocols.allows_body());
+        assert!(StatusCode::Created.allows_body());
+    }
+
+    struct FailingReader {
+        remaining: usize,
+    }
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            if self.remaining == 0 {
+                return Err(std::io::Error::other("upstream went away"));
+            }
+            let n = self.remaining.min(buf.len());
+            buf[..n].fill(b'x');
+            self.remaining -= n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_reader_body_with_known_length() {
+        let response = render(
+            Response::builder()
+                .body_reader(std::io::Cursor::new(b"streamed body".to_vec()), Some(13))
+                .build(),
+        );
+        assert!(response.contains("content-length: 13\r\n"));
+        assert!(!response.contains("transfer-encoding"));
+        assert!(response.ends_with("\r\n\r\nstreamed body"));
+    }
+
+    #[test]
+    fn test_reader_body_with_unknown_length_is_chunked() {
+        let body = vec![b'a'; 20_000];
+        let response = render(
+            Response::builder()
+                .body_reader(std::io::Cursor::new(body), None)
+                .build(),
+        );
+        assert!(response.contains("transfer-encoding: chunked\r\n"));
+        assert!(!response.contains("content-length"));
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        assert!(body.starts_with("2000\r\n"));
+        assert!(body.ends_with("\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_reader_body_for_http_1_0_closes_connection() {
+        let mut writer = ResponseWriter::with_http_version(Vec::new(), "1.0");
+        Response::builder()
+            .body_reader(std::io::Cursor::new(b"abc".to_vec()), None)
+            .build()
+            .write_with(&mut writer)
+            .unwrap();
+        let response = String::from_utf8(writer.into_inner()).unwrap();
+        assert!(response.contains("connection: close\r\n"));
+        assert!(!response.contains("transfer-encoding"));
+        assert!(response.ends_with("\r\n\r\nabc"));
+    }
+
+    #[test]
+    fn test_failing_reader_stops_without_ending_body() {
+        let mut out = Vec::new();
+        let err = Response::builder()
+            .body_reader(FailingReader { remaining: 10 }, None)
+            .build()
+            .write_to(&mut out)
+            .unwrap_err();
+        assert!(matches!(err, WriterError::Io(_)));
+        let response = String::from_utf8(out).unwrap();
+        assert!(response.ends_with("a\r\nxxxxxxxxxx\r\n"));
+    }
+
+    #[test]
+    fn test_short_reader_with_known_length_fails() {
+        let mut out = Vec::new();
+        let err = Response::builder()
+            .body_reader(std::io::Cursor::new(b"abc".to_vec()), Some(10))
+            .build()
+            .write_to(&mut out)
+            .unwrap_err();
+        assert!(matches!(err, WriterError::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_default_headers_do_not_override_handler_headers() {
+        let defaults = DefaultHeaders::new().server("http-from-tcp");
+        let mut response = Response::builder()
+            .header("Content-Type", "application/json")
+            .header("Server", "custom")
+            .build();
+        defaults.apply(&mut response);
+
+        let headers = &response.headers;
+        assert_eq!(headers.get("content-type"), Some("application/json"));
+        assert_eq!(headers.get("server"), Some("custom"));
+        assert!(headers.get("date").unwrap().parse::<HttpDate>().is_ok());
+        assert_eq!(headers.map.len(), 3);
+    }
+
+    #[test]
+    fn test_empty_default_headers_still_send_date() {
+        let mut response = Response::builder().build();
+        DefaultHeaders::empty().apply(&mut response);
+        assert_eq!(response.headers.map.len(), 1);
+        assert!(response.headers.get("date").is_some());
+    }
+
+    #[test]
+    fn test_default_content_type_skipped_without_body() {
+        let mut response = Response::builder().status(StatusCode::NotModified).build();
+        DefaultHeaders::new().apply(&mut response);
+        assert_eq!(response.headers.get("content-type"), None);
+        assert!(response.headers.get("date").is_some());
+    }
+}
@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::handler::Handler;
+use crate::middleware::{Chain, Middleware};
+use crate::request::{Method, Request};
+use crate::response::{Response, StatusCode};
+
+/// One piece of a route pattern between slashes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Static(String),
+    /// `:name`, matching exactly one path segment.
+    Param(String),
+    /// `*name`, matching one or more trailing segments.
+    Wildcard(String),
+}
+
+impl Segment {
+    // Lower ranks are more specific, so `/users/me` beats `/users/:id`,
+    // which beats `/users/*rest`.
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Static(_) => 0,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    /// Panics on a malformed pattern, since routes are fixed when the
+    /// application is written rather than supplied at runtime.
+    fn parse(pattern: &str) -> Self {
+        assert!(
+            pattern.starts_with('/'),
+            "route pattern {:?} must start with '/'",
+            pattern
+        );
+
+        let parts: Vec<&str> = split_path(pattern).collect();
+        let mut segments = Vec::with_capacity(parts.len());
+        for (i, part) in parts.iter().enumerate() {
+            let segment = if let Some(name) = part.strip_prefix(':') {
+                assert!(!name.is_empty(), "empty parameter name in {:?}", pattern);
+                Segment::Param(name.to_string())
+            } else if let Some(name) = part.strip_prefix('*') {
+                assert!(!name.is_empty(), "empty wildcard name in {:?}", pattern);
+                assert!(
+                    i == parts.len() - 1,
+                    "wildcard must be the last segment in {:?}",
+                    pattern
+                );
+                Segment::Wildcard(name.to_string())
+            } else {
+                Segment::Static(part.to_string())
+            };
+            segments.push(segment);
+        }
+
+        Self { segments }
+    }
+
+    fn prefixed(mut self, prefix: &Pattern) -> Self {
+        let mut segments = prefix.segments.clone();
+        segments.append(&mut self.segments);
+        self.segments = segments;
+        self
+    }
+
+    /// Matches `path` against the pattern, returning the captured parameters.
+    fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+        let parts: Vec<&str> = split_path(path).collect();
+        let mut params = HashMap::new();
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Static(expected) => {
+                    if parts.get(i) != Some(&expected.as_str()) {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    let part = parts.get(i)?;
+                    params.insert(name.clone(), part.to_string());
+                }
+                Segment::Wildcard(name) => {
+                    if i >= parts.len() {
+                        return None;
+                    }
+                    params.insert(name.clone(), parts[i..].join("/"));
+                    return Some(params);
+                }
+            }
+        }
+
+        if parts.len() == self.segments.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+
+    fn ranks(&self) -> Vec<u8> {
+        self.segments.iter().map(Segment::rank).collect()
+    }
+}
+
+// Empty segments are ignored, so `/users/` and `/users` are the same path.
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|part| !part.is_empty())
+}
+
+struct Route {
+    method: Method,
+    pattern: Pattern,
+    handler: Box<dyn Handler>,
+}
+
+/// Dispatches requests to handlers by method and path.
+///
+/// Patterns are made of static segments, named parameters (`/users/:id`) and
+/// a trailing wildcard (`/static/*path`). When several patterns match, the
+/// most specific one wins: static segments beat parameters, which beat
+/// wildcards. Captured values are available through [`Request::param`].
+///
+/// A `HEAD` request is answered by the `GET` route for its path unless a
+/// `HEAD` route of its own matches as well; the server leaves the body off.
+///
+/// A path with no matching pattern gets `404 Not Found`; a path that matches
+/// only under other methods gets `405 Method Not Allowed` with an `Allow`
+/// header listing them.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn route(mut self, method: Method, pattern: &str, handler: impl Handler) -> Self {
+        self.routes.push(Route {
+            method,
+            pattern: Pattern::parse(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get(self, pattern: &str, handler: impl Handler) -> Self {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post(self, pattern: &str, handler: impl Handler) -> Self {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put(self, pattern: &str, handler: impl Handler) -> Self {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn delete(self, pattern: &str, handler: impl Handler) -> Self {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    pub fn patch(self, pattern: &str, handler: impl Handler) -> Self {
+        self.route(Method::Patch, pattern, handler)
+    }
+
+    /// Mounts every route of `router` under `prefix`, so a `/:id` route
+    /// nested at `/users` answers `/users/42`.
+    pub fn nest(mut self, prefix: &str, router: Router) -> Self {
+        let prefix = Pattern::parse(prefix);
+        assert!(
+            !prefix
+                .segments
+                .iter()
+                .any(|segment| matches!(segment, Segment::Wildcard(_))),
+            "a nested router's prefix cannot contain a wildcard"
+        );
+        for route in router.routes {
+            self.routes.push(Route {
+                pattern: route.pattern.prefixed(&prefix),
+                ..route
+            });
+        }
+        self
+    }
+
+    /// Wraps every route added so far in `middleware`. Routes added later are
+    /// not affected, and neither are `404` and `405` answers. Layering a
+    /// router before nesting it applies the middleware to that group only.
+    pub fn layer(mut self, middleware: impl Middleware) -> Self {
+        let middleware: Arc<dyn Middleware> = Arc::new(middleware);
+        self.routes = self
+            .routes
+            .into_iter()
+            .map(|route| Route {
+                handler: Box::new(
+                    Chain::from_boxed(route.handler).layer_shared(Arc::clone(&middleware)),
+                ),
+                ..route
+            })
+            .collect();
+        self
+    }
+}
+
+impl Handler for Router {
+    fn handle(&self, request: &Request) -> Response {
+        self.handle_mut(&mut request.clone())
+    }
+
+    fn handle_mut(&self, request: &mut Request) -> Response {
+        let method = Method::from(request.method());
+        let path = request.path();
+
+        // Ranked by specificity, then by whether the method matched exactly
+        // rather than a `GET` route standing in for `HEAD`.
+        type Candidate<'a> = (&'a Route, (Vec<u8>, bool), HashMap<String, String>);
+        let mut best: Option<Candidate> = None;
+        let mut allowed: Vec<&str> = Vec::new();
+
+        for route in &self.routes {
+            let Some(params) = route.pattern.matches(path) else {
+                continue;
+            };
+            allowed.push(route.method.as_str());
+            if route.method == Method::Get {
+                allowed.push(Method::Head.as_str());
+            }
+            let stands_in = method == Method::Head && route.method == Method::Get;
+            if route.method != method && !stands_in {
+                continue;
+            }
+            let ranks = (route.pattern.ranks(), stands_in);
+            if best
+                .as_ref()
+                .is_none_or(|(_, best_ranks, _)| ranks < *best_ranks)
+            {
+                best = Some((route, ranks, params));
+            }
+        }
+
+        if let Some((route, _, params)) = best {
+            request.params = params;
+            return route.handler.handle_mut(request);
+        }
+
+        if allowed.is_empty() {
+            return Response::builder().status(StatusCode::NotFound).build();
+        }
+
+        allowed.sort_unstable();
+        allowed.dedup();
+        Response::builder()
+            .status(StatusCode::MethodNotAllowed)
+            .header("allow", &allowed.join(", "))
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::request_from_reader;
+    use std::io::Cursor;
+
+    fn request(method: &str, target: &str) -> Request {
+        let raw = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, target);
+        request_from_reader(&mut Cursor::new(raw.into_bytes())).unwrap()
+    }
+
+    fn body(response: Response) -> String {
+        match response.body {
+            crate::body::Body::Bytes(bytes) => String::from_utf8(bytes).unwrap(),
+            other => panic!("unexpected body {:?}", other),
+        }
+    }
+
+    fn echo(name: &'static str) -> impl Handler {
+        move |request: &Request| {
+            let mut params: Vec<_> = request
+                .params
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect();
+            params.sort();
+            Response::builder()
+                .body(format!("{} {}", name, params.join(" ")))
+                .build()
+        }
+    }
+
+    #[test]
+    fn test_static_route() {
+        let router = Router::new().get("/prime/agen", echo("agen"));
+        let response = router.handle(&request("GET", "/prime/agen"));
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(body(response), "agen ");
+    }
+
+    #[test]
+    fn test_query_string_is_ignored() {
+        let router = Router::new().get("/prime/agen", echo("agen"));
+        let response = router.handle(&request("GET", "/prime/agen?page=2"));
+        assert_eq!(body(response), "agen ");
+    }
+
+    #[test]
+    fn test_named_params() {
+        let router = Router::new().get("/users/:id/posts/:post", echo("post"));
+        let response = router.handle(&request("GET", "/users/7/posts/42"));
+        assert_eq!(body(response), "post id=7 post=42");
+    }
+
+    #[test]
+    fn test_owned_request_is_routed_in_place() {
+        struct PassThrough;
+        impl Middleware for PassThrough {}
+
+        let layered = Router::new()
+            .get("/users/:id", echo("user"))
+            .layer(PassThrough);
+        let router = Router::new().nest("/api", layered);
+
+        let mut request = request("GET", "/api/users/7");
+        let response = router.handle_mut(&mut request);
+        assert_eq!(body(response), "user id=7");
+        assert_eq!(request.params.get("id").map(String::as_str), Some("7"));
+    }
+
+    #[test]
+    fn test_wildcard_captures_tail() {
+        let router = Router::new().get("/static/*path", echo("static"));
+        let response = router.handle(&request("GET", "/static/css/site.css"));
+        assert_eq!(body(response), "static path=css/site.css");
+
+        let response = router.handle(&request("GET", "/static"));
+        assert_eq!(response.status, StatusCode::NotFound);
+    }
+
+    #[test]
+    fn test_most_specific_route_wins() {
+        let router = Router::new()
+            .get("/prime/*rest", echo("wildcard"))
+            .get("/prime/:name", echo("param"))
+            .get("/prime/agen", echo("static"));
+
+        assert_eq!(
+            body(router.handle(&request("GET", "/prime/agen"))),
+            "static "
+        );
+        assert_eq!(
+            body(router.handle(&request("GET", "/prime/other"))),
+            "param name=other"
+        );
+        assert_eq!(
+            body(router.handle(&request("GET", "/prime/a/b"))),
+            "wildcard rest=a/b"
+        );
+    }
+
+    #[test]
+    fn test_unknown_path_is_not_found() {
+        let router = Router::new().get("/prime/agen", echo("agen"));
+        let response = router.handle(&request("GET", "/prime"));
+        assert_eq!(response.status, StatusCode::NotFound);
+    }
+
+    #[test]
+    fn test_wrong_method_is_method_not_allowed() {
+        let router = Router::new()
+            .put("/users/:id", echo("put"))
+            .get("/users/:id", echo("get"))
+            .delete("/users/:id", echo("delete"))
+            .get("/users/me", echo("me"));
+
+        let response = router.handle(&request("POST", "/users/7"));
+        assert_eq!(response.status, StatusCode::MethodNotAllowed);
+        assert_eq!(
+            response.headers.get("Allow"),
+            Some("DELETE, GET, HEAD, PUT")
+        );
+    }
+
+    #[test]
+    fn test_head_falls_back_to_get() {
+        let router = Router::new()
+            .get("/users/:id", echo("get"))
+            .route(Method::Head, "/users/:id", echo("head"))
+            .get("/users/me", echo("me"))
+            .post("/login", echo("login"));
+
+        assert_eq!(body(router.handle(&request("HEAD", "/users/me"))), "me ");
+        assert_eq!(
+            body(router.handle(&request("HEAD", "/users/7"))),
+            "head id=7"
+        );
+        let response = router.handle(&request("HEAD", "/login"));
+        assert_eq!(response.status, StatusCode::MethodNotAllowed);
+        assert_eq!(response.headers.get("Allow"), Some("POST"));
+    }
+
+    #[test]
+    fn test_nested_router() {
+        let users = Router::new()
+            .get("/", echo("list"))
+            .get("/:id", echo("show"));
+        let router = Router::new().nest("/api/users", users);
+
+        assert_eq!(body(router.handle(&request("GET", "/api/users"))), "list ");
+        assert_eq!(
+            body(router.handle(&request("GET", "/api/users/3"))),
+            "show id=3"
+        );
+        assert_eq!(
+            router.handle(&request("GET", "/users/3")).status,
+            StatusCode::NotFound
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "wildcard must be the last segment")]
+    fn test_wildcard_must_be_last() {
+        Router::new().get("/files/*path/raw", echo("raw"));
+    }
+}
@@ -1,70 +1,1673 @@
-use std::io::Write;
-use std::net::{TcpListener, TcpStream};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread;
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::panic::{self, AssertUnwindSafe};
+use std::thread::{self, JoinHandle};
 use std::sync::Arc;
-use crate::response::{StatusCode, write_status_line, get_default_headers, write_headers};
+use std::time::{Duration, Instant, SystemTime};
+use crate::access_log::{AccessLog, AccessLogEntry};
+#[cfg(unix)]
+use crate::activation::{self, ActivationError, InheritedListener};
+use crate::connections::{Connection, Connections, Limits};
+use crate::handler::Handler;
+use crate::headers::Headers;
+use crate::linger::Linger;
+use crate::net::{ConnectionInfo, Listener, Stream, Waker};
+use crate::pool::ThreadPool;
+use crate::timeouts::{RequestTimeouts, SocketTimeouts};
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsConfig};
+use crate::request::{Request, RequestError, RequestReader};
+use crate::response::{DefaultHeaders, Response, StatusCode};
+use crate::writer::{ResponseWriter, WriterError};
+use socket2::{Domain, Protocol, Socket, Type};
 
 
+const DEFAULT_MAX_REQUESTS_PER_CONNECTION: usize = 100;
+const DEFAULT_WORKERS: usize = 32;
+const DEFAULT_QUEUE_SIZE: usize = 128;
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_BODY_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MIN_BODY_RATE: u64 = 1024;
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+const REJECT_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_BACKLOG: i32 = 1024;
+
+/// Why a [`Server`] could not start.
+#[derive(Debug)]
+pub enum ServerError {
+    /// The bind address could not be resolved.
+    Resolve(io::Error),
+    /// The bind address resolved to no socket addresses.
+    NoAddress,
+    /// Binding failed on every address the bind address resolved to; this
+    /// is the last one tried.
+    Bind { addr: SocketAddr, source: io::Error },
+    /// The Unix socket at `path` could not be set up.
+    BindUnix { path: PathBuf, source: io::Error },
+    /// The listeners passed in by socket activation could not be used.
+    #[cfg(unix)]
+    Activation(ActivationError),
+    Io(io::Error),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Resolve(e) => write!(f, "Failed to resolve bind address: {}", e),
+            ServerError::NoAddress => write!(f, "Bind address resolved to no addresses"),
+            ServerError::Bind { addr, source } => write!(f, "Failed to bind {}: {}", addr, source),
+            ServerError::BindUnix { path, source } => {
+                write!(f, "Failed to bind {}: {}", path.display(), source)
+            }
+            #[cfg(unix)]
+            ServerError::Activation(e) => write!(f, "Socket activation failed: {}", e),
+            ServerError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerError::Resolve(e) | ServerError::Io(e) => Some(e),
+            ServerError::Bind { source, .. } | ServerError::BindUnix { source, .. } => Some(source),
+            #[cfg(unix)]
+            ServerError::Activation(e) => Some(e),
+            ServerError::NoAddress => None,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl From<ActivationError> for ServerError {
+    fn from(e: ActivationError) -> Self {
+        ServerError::Activation(e)
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> Self {
+        ServerError::Io(e)
+    }
+}
+
+/// What the server does with a new connection when every worker is busy and
+/// the queue in front of them is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverloadPolicy {
+    /// Answer `503 Service Unavailable` with a `Retry-After` header and close
+    /// the connection.
+    Reject { retry_after: Duration },
+    /// Stop accepting until there is room, leaving new connections waiting
+    /// in the listener's backlog. A connection still waiting for room when
+    /// the server closes is answered as by `Reject`.
+    Block,
+}
+
+impl Default for OverloadPolicy {
+    fn default() -> Self {
+        OverloadPolicy::Reject {
+            retry_after: DEFAULT_RETRY_AFTER,
+        }
+    }
+}
+
+/// What the server does with a new connection that would go over
+/// [`ServerConfig::max_connections`] or
+/// [`ServerConfig::max_connections_per_ip`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Answer `503 Service Unavailable` with a `Retry-After` header and close
+    /// the connection. A TLS connection is closed without an answer, since
+    /// answering would take a handshake.
+    Reject { retry_after: Duration },
+    /// Reset the connection without reading or writing anything, the
+    /// cheapest way to shed load.
+    Reset,
+}
+
+impl Default for LimitPolicy {
+    fn default() -> Self {
+        LimitPolicy::Reject {
+            retry_after: DEFAULT_RETRY_AFTER,
+        }
+    }
+}
+
+/// Settings for a [`Server`], built up with chained setters.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    default_headers: DefaultHeaders,
+    max_requests_per_connection: usize,
+    request_timeouts: RequestTimeouts,
+    write_timeout: Option<Duration>,
+    workers: usize,
+    queue_size: usize,
+    overload_policy: OverloadPolicy,
+    limits: Limits,
+    limit_policy: LimitPolicy,
+    shutdown_timeout: Duration,
+    ipv6_only: Option<bool>,
+    backlog: i32,
+    unix_socket_mode: Option<u32>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+    access_log: Option<AccessLog>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerConfig {
+    pub fn new() -> Self {
+        Self {
+            default_headers: DefaultHeaders::new(),
+            max_requests_per_connection: DEFAULT_MAX_REQUESTS_PER_CONNECTION,
+            request_timeouts: RequestTimeouts {
+                idle: Some(DEFAULT_IDLE_TIMEOUT),
+                headers: Some(DEFAULT_HEADER_TIMEOUT),
+                body: Some(DEFAULT_BODY_TIMEOUT),
+                min_body_rate: Some(DEFAULT_MIN_BODY_RATE),
+            },
+            write_timeout: Some(DEFAULT_WRITE_TIMEOUT),
+            workers: DEFAULT_WORKERS,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            overload_policy: OverloadPolicy::default(),
+            limits: Limits::default(),
+            limit_policy: LimitPolicy::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            ipv6_only: None,
+            backlog: DEFAULT_BACKLOG,
+            unix_socket_mode: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            access_log: None,
+        }
+    }
+
+    /// Serves HTTPS, completing the TLS handshake within the header
+    /// timeout.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// For IPv6 bind addresses, whether to accept IPv6 connections only.
+    /// `false` on the unspecified address `[::]` makes a dual-stack server
+    /// that also takes IPv4 connections. Left unset, the OS default applies.
+    pub fn ipv6_only(mut self, ipv6_only: bool) -> Self {
+        self.ipv6_only = Some(ipv6_only);
+        self
+    }
+
+    /// File permissions for a Unix socket, e.g. `0o660` to let only the
+    /// owner and group connect. Left unset, the process umask decides.
+    #[cfg(unix)]
+    pub fn unix_socket_mode(mut self, mode: u32) -> Self {
+        self.unix_socket_mode = Some(mode);
+        self
+    }
+
+    /// How many connections the OS queues before the server accepts them.
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = backlog.min(i32::MAX as u32) as i32;
+        self
+    }
+
+    /// The number of worker threads, and so the most connections served at
+    /// once.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// How many accepted connections may wait for a free worker before the
+    /// overload policy applies.
+    pub fn queue_size(mut self, queue_size: usize) -> Self {
+        self.queue_size = queue_size.max(1);
+        self
+    }
+
+    pub fn overload_policy(mut self, policy: OverloadPolicy) -> Self {
+        self.overload_policy = policy;
+        self
+    }
+
+    /// The most connections open at once, counting those queued for a
+    /// worker. `None`, the default, is unlimited.
+    pub fn max_connections(mut self, max: Option<usize>) -> Self {
+        self.limits.total = max;
+        self
+    }
+
+    /// The most connections open at once from one client IP address.
+    /// Unix socket connections have no address and are not limited by this.
+    pub fn max_connections_per_ip(mut self, max: Option<usize>) -> Self {
+        self.limits.per_ip = max;
+        self
+    }
+
+    /// What to do with connections over either connection limit.
+    pub fn limit_policy(mut self, policy: LimitPolicy) -> Self {
+        self.limit_policy = policy;
+        self
+    }
+
+    /// How long [`Server::close`] lets in-flight requests run before cutting
+    /// their connections off.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// How many requests one connection may send before the server closes
+    /// it. `1` turns keep-alive off.
+    pub fn max_requests_per_connection(mut self, max: usize) -> Self {
+        self.max_requests_per_connection = max.max(1);
+        self
+    }
+
+    /// How long a connection may sit between requests, including before its
+    /// first, before the server closes it. `None` waits forever.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.request_timeouts.idle = timeout;
+        self
+    }
+
+    /// How long a client has to send the request line and headers once it
+    /// has started a request. A client that takes longer gets
+    /// `408 Request Timeout`.
+    pub fn header_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.request_timeouts.headers = timeout;
+        self
+    }
+
+    /// The base time allowed for a request body, extended by
+    /// [`min_body_rate`](Self::min_body_rate) as the body arrives. A client
+    /// that takes longer gets `408 Request Timeout`.
+    pub fn body_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.request_timeouts.body = timeout;
+        self
+    }
+
+    /// The slowest body upload accepted, in bytes per second.
+    pub fn min_body_rate(mut self, bytes_per_second: Option<u64>) -> Self {
+        self.request_timeouts.min_body_rate = bytes_per_second;
+        self
+    }
+
+    /// How long a single write to the client may block before the server
+    /// gives up on the connection.
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    /// Headers added to every response unless the handler sets them.
+    pub fn default_headers(mut self, default_headers: DefaultHeaders) -> Self {
+        self.default_headers = default_headers;
+        self
+    }
+
+    /// Records every response the server sends, including those to requests
+    /// that could not be parsed.
+    pub fn access_log(mut self, access_log: AccessLog) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+}
+
+/// What happened to the connections that were open when the server closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Connections that finished on their own, including idle keep-alive
+    /// connections that were closed between requests.
+    pub drained: usize,
+    /// Connections still busy at the shutdown deadline, which were cut off.
+    pub aborted: usize,
+    /// How long shutdown took.
+    pub elapsed: Duration,
+}
+
+/// A snapshot of a server's connections, from [`Server::stats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerStats {
+    /// Connections accepted and not yet closed, including those queued for
+    /// a worker.
+    pub open_connections: usize,
+    /// Open connections in the middle of handling a request.
+    pub busy_connections: usize,
+    /// Open connections by client IP address.
+    pub connections_per_ip: HashMap<IpAddr, usize>,
+    /// Connections accepted since the server started.
+    pub accepted: u64,
+    /// Connections turned away by a connection limit.
+    pub rejected_at_limit: u64,
+    /// Connections turned away because every worker was busy and the queue
+    /// was full.
+    pub rejected_overloaded: u64,
+}
+
+type Pool = ThreadPool<(Stream, ConnectionInfo, Connection)>;
+
 #[derive(Debug)]
 pub struct Server {
-    listener: Arc<TcpListener>,
-    closed: Arc<AtomicBool>
+    local_addr: Option<SocketAddr>,
+    unix_path: Option<PathBuf>,
+    // Whether `close` removes the socket file, which it only does for
+    // sockets the server bound itself.
+    owns_socket_file: bool,
+    waker: Arc<Waker>,
+    connections: Arc<Connections>,
+    shutdown_timeout: Duration,
+    accept_thread: JoinHandle<Pool>,
 }
 
 impl Server {
-    pub fn serve(port: u16) -> Result<Self, String> {
-        let listener = Arc::new(
-            TcpListener::bind(format!("127.0.0.1:{}", port))
-                .map_err(|e| format!("Failed to bind: {}", e))?
-        );
+    /// Listens on `127.0.0.1:port` and answers every request with `handler`.
+    pub fn serve(port: u16, handler: impl Handler) -> Result<Self, ServerError> {
+        Self::serve_with_config(port, ServerConfig::new(), handler)
+    }
 
-        let closed = Arc::new(AtomicBool::new(false));
+    pub fn serve_with_config(
+        port: u16,
+        config: ServerConfig,
+        handler: impl Handler,
+    ) -> Result<Self, ServerError> {
+        Self::bind_with_config((Ipv4Addr::LOCALHOST, port), config, handler)
+    }
+
+    /// Listens on `addr`, which may be IPv4 or IPv6 and may use port `0` to
+    /// have the OS pick a free port; [`Server::local_addr`] reports it. When
+    /// `addr` resolves to several addresses, the first that binds is used.
+    pub fn bind(addr: impl ToSocketAddrs, handler: impl Handler) -> Result<Self, ServerError> {
+        Self::bind_with_config(addr, ServerConfig::new(), handler)
+    }
 
-        let cloned_listener = Arc::clone(&listener);
-        let closed_clone = Arc::clone(&closed);
-        thread::spawn(move || {
-            for stream in cloned_listener.incoming() {
-                if closed_clone.load(Ordering::SeqCst) {
-                    println!("Server closed, stopping listener");
+    pub fn bind_with_config(
+        addr: impl ToSocketAddrs,
+        config: ServerConfig,
+        handler: impl Handler,
+    ) -> Result<Self, ServerError> {
+        let mut last_error = None;
+        let mut bound = None;
+        for addr in addr.to_socket_addrs().map_err(ServerError::Resolve)? {
+            match bind_listener(addr, &config) {
+                Ok(listener) => {
+                    bound = Some(listener);
                     break;
                 }
-                match stream {
-                    Ok(s) => {
-                        thread::spawn(|| {
-                            if let Err(e) = handle(s) {
-                                eprintln!("Error handling connection: {}", e);
-                            }
-                        });
+                Err(source) => last_error = Some(ServerError::Bind { addr, source }),
+            }
+        }
+        let listener = match bound {
+            Some(listener) => listener,
+            None => return Err(last_error.unwrap_or(ServerError::NoAddress)),
+        };
+        let local_addr = listener.local_addr()?;
+
+        Self::start(Listener::Tcp(listener), Some(local_addr), None, config, handler)
+    }
+
+    /// Serves on a listener that is already bound, such as one inherited
+    /// from a parent process for a restart without downtime.
+    pub fn from_listener(
+        listener: TcpListener,
+        handler: impl Handler,
+    ) -> Result<Self, ServerError> {
+        Self::from_listener_with_config(listener, ServerConfig::new(), handler)
+    }
+
+    /// Serves on an already bound listener. The bind options in `config`
+    /// are not applied, since the socket is already set up.
+    pub fn from_listener_with_config(
+        listener: TcpListener,
+        config: ServerConfig,
+        handler: impl Handler,
+    ) -> Result<Self, ServerError> {
+        let local_addr = listener.local_addr()?;
+        Self::start(Listener::Tcp(listener), Some(local_addr), None, config, handler)
+    }
+
+    /// Listens on a Unix domain socket at `path`. A socket file left behind
+    /// by a server that is no longer running is replaced; a live socket or
+    /// any other kind of file at `path` is an error. The socket file is
+    /// removed again by [`Server::close`].
+    #[cfg(unix)]
+    pub fn bind_unix(path: impl AsRef<Path>, handler: impl Handler) -> Result<Self, ServerError> {
+        Self::bind_unix_with_config(path, ServerConfig::new(), handler)
+    }
+
+    #[cfg(unix)]
+    pub fn bind_unix_with_config(
+        path: impl AsRef<Path>,
+        config: ServerConfig,
+        handler: impl Handler,
+    ) -> Result<Self, ServerError> {
+        let path = path.as_ref();
+        let listener = bind_unix_listener(path, &config).map_err(|source| ServerError::BindUnix {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+        let path = Some(path.to_path_buf());
+        let server = Self::start(Listener::Unix(listener), None, path, config, handler)?;
+        Ok(server.owning_socket_file())
+    }
+
+    /// Serves on a Unix socket listener that is already bound. Its socket
+    /// file, if it has one, is left in place by [`Server::close`], since
+    /// whoever bound it still owns it.
+    #[cfg(unix)]
+    pub fn from_unix_listener(
+        listener: UnixListener,
+        handler: impl Handler,
+    ) -> Result<Self, ServerError> {
+        Self::from_unix_listener_with_config(listener, ServerConfig::new(), handler)
+    }
+
+    #[cfg(unix)]
+    pub fn from_unix_listener_with_config(
+        listener: UnixListener,
+        config: ServerConfig,
+        handler: impl Handler,
+    ) -> Result<Self, ServerError> {
+        let path = listener.local_addr()?.as_pathname().map(Path::to_path_buf);
+        Self::start(Listener::Unix(listener), None, path, config, handler)
+    }
+
+    /// Serves on the listener passed in by socket activation, see
+    /// [`listen_fds`](crate::activation::listen_fds). `Ok(None)` when this
+    /// process was not started that way. Being passed more than one listener
+    /// is an error that leaves them all in place; use `listen_fds` directly
+    /// to serve on several.
+    #[cfg(unix)]
+    pub fn from_activation(
+        config: ServerConfig,
+        handler: impl Handler,
+    ) -> Result<Option<Self>, ServerError> {
+        let count = activation::passed_count()?;
+        if count > 1 {
+            return Err(ActivationError::TooManyListeners { count }.into());
+        }
+        let mut listeners = activation::listen_fds()?;
+        let Some(activated) = listeners.pop() else {
+            return Ok(None);
+        };
+        let server = match activated.listener {
+            InheritedListener::Tcp(listener) => {
+                Self::from_listener_with_config(listener, config, handler)
+            }
+            InheritedListener::Unix(listener) => {
+                Self::from_unix_listener_with_config(listener, config, handler)
+            }
+        };
+        server.map(Some)
+    }
+
+    fn start(
+        listener: Listener,
+        local_addr: Option<SocketAddr>,
+        unix_path: Option<PathBuf>,
+        config: ServerConfig,
+        handler: impl Handler,
+    ) -> Result<Self, ServerError> {
+        listener.prepare()?;
+        let waker = Arc::new(Waker::new(&listener)?);
+        let connections = Connections::new();
+        let shutdown_timeout = config.shutdown_timeout;
+        let config = Arc::new(config);
+        let handler: Arc<dyn Handler> = Arc::new(handler);
+
+        let waker_clone = Arc::clone(&waker);
+        let connections_clone = Arc::clone(&connections);
+        let accept_thread = thread::spawn(move || {
+            let pool = {
+                let config = Arc::clone(&config);
+                Pool::new(config.workers, config.queue_size, move |(s, info, connection)| {
+                    let handler = handler.as_ref();
+                    if let Err(e) = serve_connection(s, info, &config, handler, &connection) {
+                        eprintln!("Error handling connection: {}", e);
                     }
-                    Err(e) => {
-                            // Only log if we're NOT closed
-                            if !closed_clone.load(Ordering::SeqCst) {
-                                eprintln!("Error accepting connection: {}", e);
-                            }
+                })
+            };
+            let linger = Linger::new();
+
+            loop {
+                match listener.accept(&waker_clone) {
+                    Ok(Some((s, info))) => {
+                        dispatch(&pool, &linger, s, info, &config, &connections_clone)
+                    }
+                    Ok(None) => {
+                        println!("Server closed, stopping listener");
+                        break;
                     }
+                    Err(e) => eprintln!("Error accepting connection: {}", e),
                 }
             }
+
+            // Handed back so `close` decides when the workers are joined.
+            pool
         });
-        
-        Ok(Self { listener  , closed})
+
+        Ok(Self {
+            local_addr,
+            unix_path,
+            owns_socket_file: false,
+            waker,
+            connections,
+            shutdown_timeout,
+            accept_thread,
+        })
+    }
+
+    fn owning_socket_file(mut self) -> Self {
+        self.owns_socket_file = true;
+        self
+    }
+
+    /// The TCP address the server is listening on, with the actual port when
+    /// it was bound to port `0`. `None` for a Unix socket server.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Current connection counts and how many connections have been turned
+    /// away.
+    pub fn stats(&self) -> ServerStats {
+        self.connections.stats()
+    }
+
+    /// The socket file of a Unix socket server.
+    pub fn unix_path(&self) -> Option<&Path> {
+        self.unix_path.as_deref()
+    }
+
+    /// Stops accepting connections and waits for in-flight requests to
+    /// finish, up to the configured shutdown timeout. Idle keep-alive
+    /// connections are closed straight away and busy ones are answered with
+    /// `Connection: close`; whatever is still running at the deadline is cut
+    /// off. Returns once every server thread has exited, so a handler that
+    /// never returns will hold up shutdown.
+    pub fn close(self) -> ShutdownReport {
+        let started = Instant::now();
+        let deadline = started + self.shutdown_timeout;
+
+        // Closing idle connections first frees their workers, which a
+        // blocked accept loop may be waiting on.
+        self.connections.begin_close();
+
+        self.waker.wake();
+        let pool = match self.accept_thread.join() {
+            Ok(pool) => Some(pool),
+            Err(_) => {
+                eprintln!("Accept thread panicked");
+                None
+            }
+        };
+        #[cfg(unix)]
+        if let Some(path) = &self.unix_path
+            && self.owns_socket_file
+            && let Err(e) = fs::remove_file(path)
+        {
+            eprintln!("Error removing socket file {}: {}", path.display(), e);
+        }
+
+        let (drained, aborted) = self.connections.wait_closed(deadline);
+        // Joins the workers, which exit once their connections are done.
+        drop(pool);
+
+        ShutdownReport {
+            drained,
+            aborted,
+            elapsed: started.elapsed(),
+        }
+    }
+}
+
+fn bind_listener(addr: SocketAddr, config: &ServerConfig) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if let (true, Some(ipv6_only)) = (addr.is_ipv6(), config.ipv6_only) {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    // Lets a restarted server bind while old connections sit in TIME_WAIT,
+    // as `TcpListener::bind` does.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(config.backlog)?;
+    Ok(socket.into())
+}
+
+#[cfg(unix)]
+fn bind_unix_listener(path: &Path, config: &ServerConfig) -> io::Result<UnixListener> {
+    remove_stale_socket(path)?;
+    let Some(mode) = config.unix_socket_mode else {
+        return UnixListener::bind(path);
+    };
+    // The socket is created owner-only so nobody can connect in the moment
+    // before it gets `mode`. The umask is process-wide, so it is restored
+    // as soon as the socket file exists.
+    // SAFETY: `umask` only swaps the process file mode mask.
+    let umask = unsafe { libc::umask(0o177) };
+    let bound = UnixListener::bind(path);
+    // SAFETY: as above.
+    unsafe { libc::umask(umask) };
+    let listener = bound?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Removes a socket file at `path` if nothing is listening on it any more,
+/// as happens when a server exits without closing.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "path exists and is not a socket",
+        ));
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "another server is listening on this socket",
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+/// Hands a new connection to the worker pool, applying the limit policy
+/// when it would go over a connection limit and the overload policy when the
+/// pool's queue is full.
+fn dispatch(
+    pool: &Pool,
+    linger: &Linger,
+    stream: Stream,
+    info: ConnectionInfo,
+    config: &ServerConfig,
+    connections: &Arc<Connections>,
+) {
+    // Tracked from the moment it is accepted, so shutdown also accounts for
+    // connections still waiting in the queue.
+    let ip = info.peer_addr.map(|addr| addr.ip());
+    let connection = match connections.register(stream.try_clone().ok(), ip, config.limits) {
+        Ok(connection) => connection,
+        Err(_) => {
+            match config.limit_policy {
+                LimitPolicy::Reject { retry_after } => {
+                    shed(stream, &info, linger, retry_after, config)
+                }
+                LimitPolicy::Reset => stream.reset(),
+            }
+            return;
+        }
+    };
+    let queued = match config.overload_policy {
+        // Shutdown must not wait for a worker to come free.
+        OverloadPolicy::Block => {
+            pool.submit_while((stream, info, connection), || !connections.is_closing())
+        }
+        OverloadPolicy::Reject { .. } => pool.try_submit((stream, info, connection)),
+    };
+    if let Err((stream, info, _)) = queued {
+        connections.record_overloaded();
+        let retry_after = match config.overload_policy {
+            OverloadPolicy::Reject { retry_after } => retry_after,
+            OverloadPolicy::Block => DEFAULT_RETRY_AFTER,
+        };
+        shed(stream, &info, linger, retry_after, config);
+    }
+}
+
+/// Turns a connection away with a `503`, or by closing it when the client
+/// expects TLS. The request is never read, so the connection is left to
+/// linger until the client has had the chance to read the answer.
+fn shed(
+    mut stream: Stream,
+    info: &ConnectionInfo,
+    linger: &Linger,
+    retry_after: Duration,
+    config: &ServerConfig,
+) {
+    #[cfg(feature = "tls")]
+    let answer = config.tls.is_none();
+    #[cfg(not(feature = "tls"))]
+    let answer = true;
+    if answer {
+        let (time, started) = (SystemTime::now(), Instant::now());
+        match reject(&mut stream, retry_after, config) {
+            Ok(bytes) => {
+                let status = StatusCode::ServiceUnavailable.code();
+                log_access(config, info, None, (time, started), status, bytes)
+            }
+            Err(e) => eprintln!("Error rejecting connection: {}", e),
+        }
+    }
+    linger.close(stream);
+}
+
+/// Answers a connection the server has no room for without reading its
+/// request. Returns the number of body bytes sent.
+fn reject<S: Write + SocketTimeouts>(
+    mut stream: S,
+    retry_after: Duration,
+    config: &ServerConfig,
+) -> Result<u64, WriterError> {
+    // Runs on the accept thread, so a client that will not read must not
+    // hold it up.
+    stream.set_write_timeout(Some(REJECT_WRITE_TIMEOUT))?;
+    let mut response = Response::builder()
+        .status(StatusCode::ServiceUnavailable)
+        .header("retry-after", &retry_after.as_secs().max(1).to_string())
+        .header("connection", "close")
+        .build();
+    config.default_headers.apply(&mut response);
+    let mut writer = ResponseWriter::new(&mut stream);
+    response.write_with(&mut writer)?;
+    Ok(writer.body_bytes())
+}
+
+/// Serves one connection, terminating TLS first when it is configured.
+fn serve_connection(
+    stream: Stream,
+    info: ConnectionInfo,
+    config: &ServerConfig,
+    handler: &dyn Handler,
+    connection: &Connection,
+) -> Result<(), WriterError> {
+    #[cfg(feature = "tls")]
+    if let Some(tls) = &config.tls {
+        let (stream, tls_info) = match tls::accept(tls, stream, config.request_timeouts.headers) {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("TLS handshake failed: {}", e);
+                return Ok(());
+            }
+        };
+        let info = ConnectionInfo {
+            tls: Some(tls_info),
+            ..info
+        };
+        return handle(stream, config, handler, connection, &info);
+    }
+    handle(stream, config, handler, connection, &info)
+}
+
+/// Serves requests on one connection until either side asks to close it,
+/// the request limit is reached, the server shuts down, or the client goes
+/// away. Pipelined requests are answered in the order they arrive.
+fn handle<S: Read + Write + SocketTimeouts>(
+    mut stream: S,
+    config: &ServerConfig,
+    handler: &dyn Handler,
+    connection: &Connection,
+    info: &ConnectionInfo,
+) -> Result<(), WriterError> {
+    stream.set_write_timeout(config.write_timeout)?;
+    let mut requests = RequestReader::new(stream);
+    let mut served = 0;
+
+    loop {
+        if !connection.set_busy(false) {
+            return Ok(());
+        }
+        // A request counts as in progress from its first byte, so shutdown
+        // does not cut off a client partway through sending one.
+        match requests.wait_for_request_within(&config.request_timeouts) {
+            Ok(true) => {}
+            Ok(false) | Err(_) => return Ok(()),
+        }
+        connection.set_busy(true);
+        let (time, started) = (SystemTime::now(), Instant::now());
+        let request = requests.next_request_within(&config.request_timeouts);
+        let mut request = match request {
+            Ok(Some(request)) => request,
+            Ok(None) | Err(RequestError::IdleTimeout) => return Ok(()),
+            Err(e) => {
+                eprintln!("Error parsing request: {:?}", e);
+                let status = match e {
+                    RequestError::Timeout => StatusCode::RequestTimeout,
+                    RequestError::UnsupportedTransferEncoding => StatusCode::NotImplemented,
+                    _ => StatusCode::BadRequest,
+                };
+                // The rest of the stream cannot be trusted to line up with
+                // a request boundary, so this is the last response.
+                let mut response = Response::builder()
+                    .status(status)
+                    .header("connection", "close")
+                    .build();
+                config.default_headers.apply(&mut response);
+                let mut writer = ResponseWriter::new(requests.get_mut());
+                let status = response.status.code();
+                let result = response.write_with(&mut writer);
+                log_access(config, info, None, (time, started), status, writer.body_bytes());
+                return result;
+            }
+        };
+        served += 1;
+        request.extensions.insert(info.clone());
+
+        let http_version = request
+            .request_line
+            .as_ref()
+            .map(|line| line.http_version.clone())
+            .unwrap_or_else(|| "1.1".to_string());
+        let mut response = call_handler(handler, &mut request);
+
+        let keep_alive = wants_keep_alive(&request.headers, &http_version)
+            && !has_connection_token(&response.headers, "close")
+            && served < config.max_requests_per_connection
+            && !connection.is_closing()
+            && !ends_by_close(&response, &http_version);
+        if !keep_alive {
+            response.headers.set("connection", "close");
+        } else if http_version == "1.0" {
+            response.headers.set("connection", "keep-alive");
+        }
+        config.default_headers.apply(&mut response);
+
+        // Writing also flushes, so the response is sent before the next
+        // request is read or the stream drops.
+        let mut writer = ResponseWriter::with_http_version(requests.get_mut(), &http_version);
+        let status = response.status.code();
+        // An answer to HEAD describes the body it leaves off, and sending
+        // the body anyway would be read as the start of the next response.
+        let result = if request.method() == "HEAD" {
+            response.write_head_with(&mut writer)
+        } else {
+            response.write_with(&mut writer)
+        };
+        let bytes = writer.body_bytes();
+        log_access(config, info, Some(&request), (time, started), status, bytes);
+        result?;
+
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Records a response in the access log, if there is one. `request` is
+/// `None` when the request could not be parsed or was never read.
+fn log_access(
+    config: &ServerConfig,
+    info: &ConnectionInfo,
+    request: Option<&Request>,
+    (time, started): (SystemTime, Instant),
+    status: u16,
+    bytes: u64,
+) {
+    let Some(access_log) = &config.access_log else {
+        return;
+    };
+    let header = |name| request.and_then(|r| r.headers.get(name)).map(str::to_string);
+    access_log.log(&AccessLogEntry {
+        time,
+        peer_addr: info.peer_addr,
+        request_line: request.and_then(|r| r.request_line.clone()),
+        status,
+        bytes,
+        duration: started.elapsed(),
+        user_agent: header("user-agent"),
+        referer: header("referer"),
+    });
+}
+
+/// HTTP/1.1 connections persist unless the client sends `Connection: close`;
+/// HTTP/1.0 ones only when it sends `Connection: keep-alive`.
+fn wants_keep_alive(request_headers: &Headers, http_version: &str) -> bool {
+    if http_version == "1.0" {
+        has_connection_token(request_headers, "keep-alive")
+    } else {
+        !has_connection_token(request_headers, "close")
     }
+}
+
+fn has_connection_token(headers: &Headers, token: &str) -> bool {
+    headers.get("connection").is_some_and(|value| {
+        value
+            .split(',')
+            .any(|option| option.trim().eq_ignore_ascii_case(token))
+    })
+}
 
-    pub fn close(&self) {
-       self.closed.store(true, Ordering::SeqCst);
+/// Whether the response can only be delimited by closing the connection: a
+/// streamed body of unknown length sent to a client that cannot take chunked
+/// coding.
+fn ends_by_close(response: &Response, http_version: &str) -> bool {
+    http_version == "1.0" && response.status.allows_body() && response.body.len().is_none()
+}
 
+/// Runs the handler, turning a panic into a `500` so one bad request cannot
+/// take down the connection thread without an answer.
+fn call_handler(handler: &dyn Handler, request: &mut Request) -> Response {
+    match panic::catch_unwind(AssertUnwindSafe(|| handler.handle_mut(request))) {
+        Ok(response) => response,
+        Err(_) => {
+            eprintln!("Handler panicked while handling request");
+            Response::builder()
+                .status(StatusCode::InternalServerError)
+                .build()
+        }
     }
 }
 
-fn handle(mut stream: TcpStream) -> Result<(), std::io::Error> {
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::net::{Ipv6Addr, TcpStream};
+
+    struct MockStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl SocketTimeouts for MockStream {
+        fn set_read_timeout(&mut self, _: Option<Duration>) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn set_write_timeout(&mut self, _: Option<Duration>) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn exchange(input: &str, handler: impl Handler) -> String {
+        exchange_with(input, &ServerConfig::new(), handler)
+    }
+
+    fn exchange_with(input: &str, config: &ServerConfig, handler: impl Handler) -> String {
+        exchange_bytes(input.as_bytes(), config, handler)
+    }
+
+    fn exchange_bytes(input: &[u8], config: &ServerConfig, handler: impl Handler) -> String {
+        let mut stream = MockStream {
+            input: Cursor::new(input.to_vec()),
+            output: Vec::new(),
+        };
+        let connections = Connections::new();
+        let connection = connections.register(None, None, Limits::default()).unwrap();
+        handle(&mut stream, config, &handler, &connection, &ConnectionInfo::default()).unwrap();
+        String::from_utf8(stream.output).unwrap()
+    }
+
+    fn echo_path(request: &Request) -> Response {
+        Response::builder().body(request.path().to_string()).build()
+    }
+
+    #[test]
+    fn test_handler_response_is_written() {
+        let input = "GET /coffee HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let response = exchange(input, |request: &Request| {
+            let target = &request.request_line.as_ref().unwrap().request_target;
+            Response::builder()
+                .body(format!("you asked for {}", target))
+                .build()
+        });
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("date: "));
+        assert!(response.ends_with("\r\n\r\nyou asked for /coffee"));
+    }
+
+    #[test]
+    fn test_parse_error_is_bad_request() {
+        let response = exchange("/coffee HTTP/1.1\r\n\r\n", |_: &Request| {
+            Response::builder().build()
+        });
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn test_handler_panic_is_internal_server_error() {
+        let response = exchange("GET / HTTP/1.1\r\n\r\n", |_: &Request| -> Response {
+            panic!("handler bug")
+        });
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+    }
+
+    #[test]
+    fn test_pipelined_requests_are_answered_in_order() {
+        let input = "GET /one HTTP/1.1\r\n\r\n\
+                     POST /two HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc\
+                     GET /three HTTP/1.1\r\n\r\n";
+        let response = exchange(input, echo_path);
+
+        let one = response.find("\r\n\r\n/one").unwrap();
+        let two = response.find("\r\n\r\n/two").unwrap();
+        let three = response.find("\r\n\r\n/three").unwrap();
+        assert!(one < two && two < three);
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 3);
+        assert!(!response.contains("connection: close"));
+    }
+
+    #[test]
+    fn test_head_response_has_no_body() {
+        let input = "HEAD /one HTTP/1.1\r\n\r\n\
+                     GET /two HTTP/1.1\r\n\r\n";
+        let response = exchange(input, echo_path);
+
+        let second = response.rfind("HTTP/1.1 200 OK").unwrap();
+        let (head, get) = response.split_at(second);
+        assert!(head.contains("content-length: 4\r\n"));
+        assert!(head.ends_with("\r\n\r\n") && !head.contains("/one"));
+        assert!(get.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get.ends_with("\r\n\r\n/two"));
+    }
+
+    #[test]
+    fn test_access_log_records_each_response() {
+        let path = std::env::temp_dir().join(format!(
+            "http-from-tcp-{}-server-access.log",
+            std::process::id()
+        ));
+        let access_log = AccessLog::file(&path)
+            .unwrap()
+            .format(crate::access_log::LogFormat::Combined);
+        let config = ServerConfig::new().access_log(access_log);
+        let input = "GET /one HTTP/1.1\r\nUser-Agent: test/1.0\r\nReferer: /start\r\n\r\n\
+                     GET /two HTTP/1.1\r\n\r\n\
+                     /broken\r\n\r\n";
+        exchange_with(input, &config, echo_path);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("- - - ["));
+        assert!(lines[0].ends_with("] \"GET /one HTTP/1.1\" 200 4 \"/start\" \"test/1.0\""));
+        assert!(lines[1].ends_with("] \"GET /two HTTP/1.1\" 200 4 \"-\" \"-\""));
+        assert!(lines[2].ends_with("] \"-\" 400 - \"-\" \"-\""));
+    }
+
+    #[test]
+    fn test_decompression_decodes_request_bodies() {
+        use crate::compression::Decompression;
+        use crate::middleware::Chain;
+        use flate2::write::GzEncoder;
+
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"hello, handler").unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let mut input = format!(
+            "POST /upload HTTP/1.1\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+            gzipped.len()
+        )
+        .into_bytes();
+        input.extend_from_slice(&gzipped);
+        input.extend_from_slice(
+            b"POST /upload HTTP/1.1\r\nContent-Encoding: br\r\nContent-Length: 1\r\n\r\nx",
+        );
+
+        let echo_body = |request: &Request| Response::builder().body(request.body.clone()).build();
+        let chain = Chain::new(echo_body).layer(Decompression::new(1024));
+        let response = exchange_bytes(&input, &ServerConfig::new(), chain);
+        assert!(response.contains("\r\n\r\nhello, handler"));
+        assert!(response.contains("HTTP/1.1 415 Unsupported Media Type\r\n"));
+    }
+
+    #[test]
+    fn test_chunked_request_body_is_not_read_as_next_request() {
+        // Were the body ignored, the smuggled request inside it would be
+        // answered on the kept-alive connection.
+        let smuggled = "GET /smuggled HTTP/1.1\r\n\r\n";
+        let input = format!(
+            "POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n\
+             GET /next HTTP/1.1\r\n\r\n",
+            smuggled.len(),
+            smuggled
+        );
+        let response = exchange(&input, echo_path);
+        assert!(response.starts_with("HTTP/1.1 501 Not Implemented\r\n"));
+        assert!(response.contains("connection: close\r\n"));
+        assert_eq!(response.matches("HTTP/1.1 ").count(), 1);
+
+        let input = "POST /upload HTTP/1.1\r\n\
+                     Transfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n\
+                     0\r\n\r\nGET /next HTTP/1.1\r\n\r\n";
+        let response = exchange(input, echo_path);
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert_eq!(response.matches("HTTP/1.1 ").count(), 1);
+    }
+
+    #[test]
+    fn test_client_connection_close_ends_connection() {
+        let input = "GET /one HTTP/1.1\r\nConnection: close\r\n\r\n\
+                     GET /two HTTP/1.1\r\n\r\n";
+        let response = exchange(input, echo_path);
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 1);
+        assert!(response.contains("connection: close\r\n"));
+    }
+
+    #[test]
+    fn test_handler_connection_close_ends_connection() {
+        let input = "GET /one HTTP/1.1\r\n\r\nGET /two HTTP/1.1\r\n\r\n";
+        let response = exchange(input, |_: &Request| {
+            Response::builder().header("Connection", "close").build()
+        });
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 1);
+    }
+
+    #[test]
+    fn test_http_1_0_closes_unless_keep_alive_requested() {
+        let input = "GET /one HTTP/1.0\r\n\r\nGET /two HTTP/1.0\r\n\r\n";
+        let response = exchange(input, echo_path);
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 1);
+        assert!(response.contains("connection: close\r\n"));
+
+        let input = "GET /one HTTP/1.0\r\nConnection: keep-alive\r\n\r\n\
+                     GET /two HTTP/1.0\r\n\r\n";
+        let response = exchange(input, echo_path);
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(response.contains("connection: keep-alive\r\n"));
+    }
+
+    #[test]
+    fn test_max_requests_per_connection() {
+        let config = ServerConfig::new().max_requests_per_connection(2);
+        let input = "GET /1 HTTP/1.1\r\n\r\n\
+                     GET /2 HTTP/1.1\r\n\r\n\
+                     GET /3 HTTP/1.1\r\n\r\n";
+        let response = exchange_with(input, &config, echo_path);
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2);
+        assert_eq!(response.matches("connection: close\r\n").count(), 1);
+        assert!(response.ends_with("/2"));
+    }
+
+    #[test]
+    fn test_reject_sends_service_unavailable() {
+        let config = ServerConfig::new();
+        let mut stream = MockStream {
+            input: Cursor::new(Vec::new()),
+            output: Vec::new(),
+        };
+        reject(&mut stream, Duration::from_secs(5), &config).unwrap();
+        let response = String::from_utf8(stream.output).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("retry-after: 5\r\n"));
+        assert!(response.contains("connection: close\r\n"));
+    }
+
+    fn slow_handler(delay: Duration) -> impl Handler {
+        move |_: &Request| {
+            thread::sleep(delay);
+            Response::builder().body("done").build()
+        }
+    }
+
+    fn send(addr: SocketAddr, request: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        stream
+    }
+
+    fn read_to_end(mut stream: TcpStream) -> String {
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response
+    }
+
+    #[test]
+    fn test_close_drains_in_flight_request() {
+        let server = Server::bind("127.0.0.1:0", slow_handler(Duration::from_millis(200))).unwrap();
+
+        let client = send(server.local_addr().unwrap(), "GET / HTTP/1.1\r\n\r\n");
+        thread::sleep(Duration::from_millis(50));
+        let report = server.close();
+
+        assert_eq!(report.drained, 1);
+        assert_eq!(report.aborted, 0);
+        let response = read_to_end(client);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("connection: close\r\n"));
+    }
+
+    #[test]
+    fn test_close_does_not_wait_for_idle_connections() {
+        let server = Server::bind("127.0.0.1:0", ok).unwrap();
+
+        let mut client = send(server.local_addr().unwrap(), "GET / HTTP/1.1\r\n\r\n");
+        let mut buffer = [0u8; 1024];
+        assert!(client.read(&mut buffer).unwrap() > 0);
+
+        let report = server.close();
+        assert_eq!(report.drained, 1);
+        assert_eq!(report.aborted, 0);
+        assert!(report.elapsed < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_close_waits_for_request_being_sent() {
+        let server = Server::bind("127.0.0.1:0", ok).unwrap();
+
+        let mut client = send(
+            server.local_addr().unwrap(),
+            "POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nab",
+        );
+        thread::sleep(Duration::from_millis(50));
+        let closer = thread::spawn(move || server.close());
+        thread::sleep(Duration::from_millis(50));
+        client.write_all(b"cd").unwrap();
+
+        let response = read_to_end(client);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("connection: close\r\n"));
+        let report = closer.join().unwrap();
+        assert_eq!(report.drained, 1);
+        assert_eq!(report.aborted, 0);
+    }
+
+    #[test]
+    fn test_close_aborts_requests_past_deadline() {
+        let config = ServerConfig::new().shutdown_timeout(Duration::from_millis(50));
+        let handler = slow_handler(Duration::from_millis(500));
+        let server = Server::bind_with_config("127.0.0.1:0", config, handler).unwrap();
+
+        let _client = send(server.local_addr().unwrap(), "GET / HTTP/1.1\r\n\r\n");
+        thread::sleep(Duration::from_millis(50));
+        let report = server.close();
+
+        assert_eq!(report.drained, 0);
+        assert_eq!(report.aborted, 1);
+    }
+
+    fn ok(_: &Request) -> Response {
+        Response::builder().body("ok").build()
+    }
+
+    fn get(addr: SocketAddr) -> String {
+        read_to_end(send(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n"))
+    }
+
+    /// Sends a request with a large body and reads the answer. Connections
+    /// that are turned away are answered without their requests being read,
+    /// and the answer must still reach a client that is busy sending one.
+    fn post_large(addr: SocketAddr) -> String {
+        let body = "x".repeat(1 << 20);
+        let head = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", body.len());
+        read_to_end(send(addr, &(head + &body)))
+    }
+
+    /// Opens a keep-alive connection and waits for its first response, so
+    /// the server is sure to be tracking it.
+    fn open_idle_connection(addr: SocketAddr) -> TcpStream {
+        let mut client = send(addr, "GET / HTTP/1.1\r\n\r\n");
+        let mut buffer = [0u8; 1024];
+        assert!(client.read(&mut buffer).unwrap() > 0);
+        client
+    }
+
+    #[test]
+    fn test_connection_limit_rejects_with_503() {
+        let config = ServerConfig::new().max_connections(Some(1));
+        let server = Server::bind_with_config("127.0.0.1:0", config, ok).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let _first = open_idle_connection(addr);
+        let response = post_large(addr);
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("retry-after: 1\r\n"));
+
+        let stats = server.stats();
+        assert_eq!(stats.open_connections, 1);
+        assert_eq!(stats.busy_connections, 0);
+        assert_eq!(stats.accepted, 2);
+        assert_eq!(stats.rejected_at_limit, 1);
+        assert_eq!(stats.rejected_overloaded, 0);
+        server.close();
+    }
+
+    #[test]
+    fn test_access_log_records_turned_away_connections() {
+        let path = std::env::temp_dir().join(format!(
+            "http-from-tcp-{}-server-turned-away.log",
+            std::process::id()
+        ));
+        let config = ServerConfig::new()
+            .max_connections(Some(1))
+            .access_log(AccessLog::file(&path).unwrap());
+        let server = Server::bind_with_config("127.0.0.1:0", config, ok).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let _first = open_idle_connection(addr);
+        assert!(post_large(addr).starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        server.close();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("] \"GET / HTTP/1.1\" 200 2"));
+        assert!(lines[1].starts_with("127.0.0.1 - - ["));
+        assert!(lines[1].ends_with("] \"-\" 503 -"));
+    }
+
+    #[test]
+    fn test_per_ip_limit_resets_connection() {
+        let config = ServerConfig::new()
+            .max_connections_per_ip(Some(1))
+            .limit_policy(LimitPolicy::Reset);
+        let server = Server::bind_with_config("127.0.0.1:0", config, ok).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let mut first = open_idle_connection(addr);
+        let localhost = IpAddr::from(Ipv4Addr::LOCALHOST);
+        assert_eq!(server.stats().connections_per_ip.get(&localhost), Some(&1));
+
+        // Nothing is sent, since a write can itself fail with the reset.
+        let mut second = TcpStream::connect(addr).unwrap();
+        let mut buffer = [0u8; 1024];
+        let error = second.read(&mut buffer).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+
+        assert_eq!(server.stats().rejected_at_limit, 1);
+
+        // The connection within the limit is unaffected.
+        first.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_to_end(first).ends_with("\r\n\r\nok"));
+        server.close();
+    }
+
+    #[test]
+    fn test_closed_connection_frees_its_slot() {
+        let config = ServerConfig::new().max_connections(Some(1));
+        let server = Server::bind_with_config("127.0.0.1:0", config, ok).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        for _ in 0..3 {
+            assert!(get(addr).ends_with("\r\n\r\nok"));
+            // The server drops its side just after the response is sent.
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while server.stats().open_connections > 0 && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+        assert_eq!(server.stats().rejected_at_limit, 0);
+        server.close();
+    }
+
+    #[test]
+    fn test_overload_rejects_with_503() {
+        let config = ServerConfig::new()
+            .workers(1)
+            .queue_size(1)
+            .overload_policy(OverloadPolicy::Reject { retry_after: Duration::from_secs(2) });
+        let handler = slow_handler(Duration::from_millis(200));
+        let server = Server::bind_with_config("127.0.0.1:0", config, handler).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        // One connection keeps the worker busy and another fills the queue.
+        let busy = send(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        thread::sleep(Duration::from_millis(50));
+        let queued = send(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        thread::sleep(Duration::from_millis(50));
+
+        let response = post_large(addr);
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("retry-after: 2\r\n"));
+        assert_eq!(server.stats().rejected_overloaded, 1);
+
+        assert!(read_to_end(busy).ends_with("\r\n\r\ndone"));
+        assert!(read_to_end(queued).ends_with("\r\n\r\ndone"));
+        server.close();
+    }
+
+    #[test]
+    fn test_close_does_not_wait_for_room_in_blocked_queue() {
+        let config = ServerConfig::new()
+            .workers(1)
+            .queue_size(1)
+            .overload_policy(OverloadPolicy::Block)
+            .shutdown_timeout(Duration::from_millis(100));
+        let handler = slow_handler(Duration::from_millis(500));
+        let server = Server::bind_with_config("127.0.0.1:0", config, handler).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        // The accept loop is left waiting for room for the third connection.
+        let _busy = send(addr, "GET / HTTP/1.1\r\n\r\n");
+        thread::sleep(Duration::from_millis(50));
+        let _queued = send(addr, "GET / HTTP/1.1\r\n\r\n");
+        thread::sleep(Duration::from_millis(50));
+        let waiting = send(addr, "GET / HTTP/1.1\r\n\r\n");
+        thread::sleep(Duration::from_millis(50));
+
+        let closer = thread::spawn(move || server.close());
+        let started = Instant::now();
+        let response = read_to_end(waiting);
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(started.elapsed() < Duration::from_millis(300));
+        closer.join().unwrap();
+    }
+
+    #[test]
+    fn test_port_zero_reports_assigned_port() {
+        let first = Server::bind("127.0.0.1:0", ok).unwrap();
+        let second = Server::bind("127.0.0.1:0", ok).unwrap();
+        assert_ne!(first.local_addr().unwrap().port(), 0);
+        assert_ne!(first.local_addr().unwrap(), second.local_addr().unwrap());
+        assert!(get(first.local_addr().unwrap()).ends_with("\r\n\r\nok"));
+        first.close();
+        second.close();
+    }
+
+    #[test]
+    fn test_bind_ipv6() {
+        let server = Server::bind("[::1]:0", ok).unwrap();
+        assert!(server.local_addr().unwrap().is_ipv6());
+        assert!(get(server.local_addr().unwrap()).ends_with("\r\n\r\nok"));
+        server.close();
+    }
+
+    #[test]
+    fn test_dual_stack_accepts_ipv4() {
+        let config = ServerConfig::new().ipv6_only(false);
+        let server = Server::bind_with_config("[::]:0", config, ok).unwrap();
+        let port = server.local_addr().unwrap().port();
+        assert!(get(SocketAddr::from((Ipv4Addr::LOCALHOST, port))).ends_with("\r\n\r\nok"));
+        assert!(get(SocketAddr::from((Ipv6Addr::LOCALHOST, port))).ends_with("\r\n\r\nok"));
+        server.close();
+    }
+
+    #[test]
+    fn test_ipv6_only_refuses_ipv4() {
+        let config = ServerConfig::new().ipv6_only(true);
+        let server = Server::bind_with_config("[::]:0", config, ok).unwrap();
+        let port = server.local_addr().unwrap().port();
+        assert!(TcpStream::connect((Ipv4Addr::LOCALHOST, port)).is_err());
+        server.close();
+    }
+
+    #[test]
+    fn test_bind_error_is_typed() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap();
+        match Server::bind(addr, ok) {
+            Err(ServerError::Bind { addr: failed, source }) => {
+                assert_eq!(failed, addr);
+                assert_eq!(source.kind(), io::ErrorKind::AddrInUse);
+            }
+            other => panic!("expected a bind error, got {:?}", other.map(|s| s.local_addr())),
+        }
+    }
+
+    #[test]
+    fn test_unresolvable_address() {
+        let empty: &[SocketAddr] = &[];
+        assert!(matches!(Server::bind(empty, ok), Err(ServerError::NoAddress)));
+    }
+
+    #[test]
+    fn test_serves_on_existing_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let server = Server::from_listener(listener, ok).unwrap();
+        assert_eq!(server.local_addr(), Some(addr));
+        assert!(get(addr).ends_with("\r\n\r\nok"));
+        server.close();
+    }
+
+    #[test]
+    fn test_tcp_connection_info_reaches_handler() {
+        let server = Server::bind("127.0.0.1:0", |request: &Request| {
+            let info = request.extensions.get::<ConnectionInfo>().unwrap();
+            let peer = info.peer_addr.unwrap();
+            Response::builder().body(peer.ip().to_string()).build()
+        })
+        .unwrap();
+        assert!(get(server.local_addr().unwrap()).ends_with("\r\n\r\n127.0.0.1"));
+        server.close();
+    }
+
+    #[cfg(unix)]
+    fn socket_path(name: &str) -> PathBuf {
+        let name = format!("http-from-tcp-{}-{}.sock", std::process::id(), name);
+        let path = std::env::temp_dir().join(name);
+        let _ = fs::remove_file(&path);
+        path
+    }
 
-    write_status_line(&mut stream, StatusCode::OK)?;
-    let headers = get_default_headers(0);
+    #[cfg(unix)]
+    fn unix_get(path: &Path) -> String {
+        let mut stream = UnixStream::connect(path).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_serves_requests() {
+        let path = socket_path("serve");
+        let server = Server::bind_unix(&path, ok).unwrap();
+        assert_eq!(server.unix_path(), Some(path.as_path()));
+        assert_eq!(server.local_addr(), None);
+        assert!(unix_get(&path).ends_with("\r\n\r\nok"));
+
+        server.close();
+        assert!(!path.exists());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_unix_peer_credentials_reach_handler() {
+        let path = socket_path("credentials");
+        let server = Server::bind_unix(&path, |request: &Request| {
+            let info = request.extensions.get::<ConnectionInfo>().unwrap();
+            let cred = info.peer_credentials.unwrap();
+            Response::builder()
+                .body(format!("{} {} {:?}", cred.uid, cred.gid, cred.pid))
+                .build()
+        })
+        .unwrap();
+
+        // SAFETY: these calls only read the process's own ids.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let expected = format!("{} {} Some({})", uid, gid, std::process::id());
+        assert!(unix_get(&path).ends_with(&expected));
+        server.close();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stale_unix_socket_is_replaced() {
+        let path = socket_path("stale");
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let server = Server::bind_unix(&path, ok).unwrap();
+        assert!(unix_get(&path).ends_with("\r\n\r\nok"));
+        server.close();
+    }
 
-    write_headers(&mut stream, &headers)?;
-    
-    // 4. Flush to ensure it's sent
-    stream.flush()?;
-    
-    Ok(())
-}
\ No newline at end of file
+    #[cfg(unix)]
+    #[test]
+    fn test_live_unix_socket_is_not_replaced() {
+        let path = socket_path("live");
+        let first = Server::bind_unix(&path, ok).unwrap();
+        match Server::bind_unix(&path, ok) {
+            Err(ServerError::BindUnix { source, .. }) => {
+                assert_eq!(source.kind(), io::ErrorKind::AddrInUse)
+            }
+            Err(e) => panic!("expected AddrInUse, got {}", e),
+            Ok(_) => panic!("bound a socket that is in use"),
+        }
+        assert!(unix_get(&path).ends_with("\r\n\r\nok"));
+        first.close();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_regular_file_is_not_replaced() {
+        let path = socket_path("file");
+        fs::write(&path, "keep me").unwrap();
+        assert!(matches!(Server::bind_unix(&path, ok), Err(ServerError::BindUnix { .. })));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep me");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_existing_unix_listener_keeps_socket_file() {
+        let path = socket_path("existing");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = Server::from_unix_listener(listener, ok).unwrap();
+        assert_eq!(server.unix_path(), Some(path.as_path()));
+        assert!(unix_get(&path).ends_with("\r\n\r\nok"));
+
+        server.close();
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_mode() {
+        let path = socket_path("mode");
+        let config = ServerConfig::new().unix_socket_mode(0o600);
+        let server = Server::bind_unix_with_config(&path, config, ok).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        server.close();
+    }
+}
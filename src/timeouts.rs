@@ -0,0 +1,126 @@
+use std::io;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// A connection whose reads and writes can be given a time limit.
+pub trait SocketTimeouts {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl SocketTimeouts for TcpStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+impl<T: SocketTimeouts + ?Sized> SocketTimeouts for &mut T {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_write_timeout(timeout)
+    }
+}
+
+/// Time limits on reading one request. `None` leaves that phase unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestTimeouts {
+    /// How long to wait for the first byte of a request.
+    pub idle: Option<Duration>,
+    /// How long the request line and headers may take, counted from their
+    /// first byte.
+    pub headers: Option<Duration>,
+    /// How long the body may take on top of what `min_body_rate` allows for
+    /// the bytes received so far.
+    pub body: Option<Duration>,
+    /// The slowest acceptable body upload, in bytes per second. Every `n`
+    /// bytes received earn `n / min_body_rate` more seconds on top of
+    /// `body`, so a client that falls behind this rate times out however
+    /// long a body it declared.
+    pub min_body_rate: Option<u64>,
+}
+
+impl RequestTimeouts {
+    /// No limits at all.
+    pub fn none() -> Self {
+        Self {
+            idle: None,
+            headers: None,
+            body: None,
+            min_body_rate: None,
+        }
+    }
+
+    /// When the next byte of a body whose first byte was expected at `start`
+    /// must arrive, given that `received` bytes of it already have. `None`
+    /// when there is no limit, or the limit is too far off to represent.
+    pub(crate) fn body_deadline(&self, start: Instant, received: u64) -> Option<Instant> {
+        let base = self.body?;
+        let allowance = match self.min_body_rate {
+            Some(rate) if rate > 0 => {
+                let nanos = (received % rate) as u128 * 1_000_000_000 / rate as u128;
+                Duration::from_secs(received / rate) + Duration::from_nanos(nanos as u64)
+            }
+            _ => Duration::ZERO,
+        };
+        start.checked_add(base)?.checked_add(allowance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_body_deadline_grows_with_bytes_received() {
+        let timeouts = RequestTimeouts {
+            body: Some(Duration::from_secs(2)),
+            min_body_rate: Some(1000),
+            ..RequestTimeouts::none()
+        };
+        let start = Instant::now();
+        assert_eq!(
+            timeouts.body_deadline(start, 5000),
+            Some(start + Duration::from_secs(7))
+        );
+    }
+
+    #[test]
+    fn test_body_deadline_does_not_overflow() {
+        let timeouts = RequestTimeouts {
+            body: Some(Duration::from_secs(2)),
+            min_body_rate: Some(1),
+            ..RequestTimeouts::none()
+        };
+        let start = Instant::now();
+        assert_eq!(timeouts.body_deadline(start, u64::MAX), None);
+        let timeouts = RequestTimeouts {
+            min_body_rate: Some(u64::MAX),
+            ..timeouts
+        };
+        assert_eq!(
+            timeouts.body_deadline(start, u64::MAX - 1),
+            Some(start + Duration::from_secs(2) + Duration::from_nanos(999_999_999))
+        );
+    }
+
+    #[test]
+    fn test_body_deadline_without_rate() {
+        let timeouts = RequestTimeouts {
+            body: Some(Duration::from_secs(2)),
+            ..RequestTimeouts::none()
+        };
+        let start = Instant::now();
+        assert_eq!(
+            timeouts.body_deadline(start, 5000),
+            Some(start + Duration::from_secs(2))
+        );
+        assert_eq!(RequestTimeouts::none().body_deadline(start, 5000), None);
+    }
+}
@@ -0,0 +1,487 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ::ring::digest::{self, SHA256};
+use rustls::crypto::{CryptoProvider, ring};
+use rustls::pki_types::pem::{self, PemObject};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConnection, StreamOwned};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+
+use crate::net::Stream;
+use crate::timeouts::SocketTimeouts;
+
+/// The only protocol the server speaks, advertised with ALPN.
+const ALPN_HTTP_1_1: &[u8] = b"http/1.1";
+
+/// The TLS details of a connection, found in its
+/// [`ConnectionInfo`](crate::net::ConnectionInfo).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// The protocol version, such as `TLSv1_3`.
+    pub protocol_version: String,
+    /// The cipher suite, such as `TLS13_AES_256_GCM_SHA384`.
+    pub cipher_suite: String,
+    /// The protocol agreed through ALPN, if the client offered any.
+    pub alpn_protocol: Option<String>,
+    /// The host name the client asked for through SNI.
+    pub server_name: Option<String>,
+    /// The certificate the client authenticated with, once verified against
+    /// the configured CA bundle.
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+/// The identity in a verified client certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// The subject's distinguished name, such as `CN=billing, O=Example`.
+    pub subject: String,
+    pub subject_alt_names: Vec<SubjectAltName>,
+    /// The SHA-256 digest of the certificate's DER encoding, as lowercase
+    /// hex.
+    pub fingerprint: String,
+}
+
+/// A name a certificate holds besides its subject.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubjectAltName {
+    Dns(String),
+    Email(String),
+    Uri(String),
+    Ip(IpAddr),
+}
+
+impl ClientCertificate {
+    fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        let names = match cert.subject_alternative_name().ok()? {
+            Some(extension) => &extension.value.general_names[..],
+            None => &[],
+        };
+        let subject_alt_names = names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some(SubjectAltName::Dns(name.to_string())),
+                GeneralName::RFC822Name(email) => Some(SubjectAltName::Email(email.to_string())),
+                GeneralName::URI(uri) => Some(SubjectAltName::Uri(uri.to_string())),
+                GeneralName::IPAddress(ip) => ip_from_bytes(ip).map(SubjectAltName::Ip),
+                _ => None,
+            })
+            .collect();
+        let digest = digest::digest(&SHA256, der);
+        Some(Self {
+            subject: cert.subject().to_string(),
+            subject_alt_names,
+            fingerprint: digest
+                .as_ref()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+        })
+    }
+}
+
+fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => <[u8; 4]>::try_from(bytes).ok().map(IpAddr::from),
+        16 => <[u8; 16]>::try_from(bytes).ok().map(IpAddr::from),
+        _ => None,
+    }
+}
+
+/// Why a certificate could not be loaded.
+#[derive(Debug)]
+pub enum TlsError {
+    /// A PEM file could not be read.
+    Read { path: PathBuf, source: io::Error },
+    /// The PEM data is malformed.
+    Pem(pem::Error),
+    /// The certificate chain holds no certificates.
+    NoCertificate,
+    /// No private key was found.
+    NoPrivateKey,
+    /// The private key is unsupported or does not belong to the certificate.
+    Key(rustls::Error),
+    /// A CA certificate could not be used as a trust anchor.
+    Ca(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Read { path, source } => {
+                write!(f, "Failed to read {}: {}", path.display(), source)
+            }
+            TlsError::Pem(e) => write!(f, "Invalid PEM data: {}", e),
+            TlsError::NoCertificate => write!(f, "No certificate found"),
+            TlsError::NoPrivateKey => write!(f, "No private key found"),
+            TlsError::Key(e) => write!(f, "Unusable private key: {}", e),
+            TlsError::Ca(e) => write!(f, "Unusable CA certificate: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TlsError::Read { source, .. } => Some(source),
+            TlsError::Pem(e) => Some(e),
+            TlsError::Key(e) | TlsError::Ca(e) => Some(e),
+            TlsError::NoCertificate | TlsError::NoPrivateKey => None,
+        }
+    }
+}
+
+/// A certificate chain with its private key.
+#[derive(Debug, Clone)]
+pub struct Certificate {
+    key: Arc<CertifiedKey>,
+}
+
+impl Certificate {
+    /// Loads a PEM certificate chain, end-entity certificate first, and the
+    /// PEM private key that goes with it.
+    pub fn from_pem(cert_chain: &[u8], private_key: &[u8]) -> Result<Self, TlsError> {
+        let chain = CertificateDer::pem_slice_iter(cert_chain)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(TlsError::Pem)?;
+        if chain.is_empty() {
+            return Err(TlsError::NoCertificate);
+        }
+        let key = PrivateKeyDer::from_pem_slice(private_key).map_err(|e| match e {
+            pem::Error::NoItemsFound => TlsError::NoPrivateKey,
+            e => TlsError::Pem(e),
+        })?;
+        let key = CertifiedKey::from_der(chain, key, &provider()).map_err(TlsError::Key)?;
+        Ok(Self { key: Arc::new(key) })
+    }
+
+    pub fn from_pem_files(
+        cert_chain: impl AsRef<Path>,
+        private_key: impl AsRef<Path>,
+    ) -> Result<Self, TlsError> {
+        Self::from_pem(&read(cert_chain.as_ref())?, &read(private_key.as_ref())?)
+    }
+}
+
+/// The CA certificates client certificates are verified against.
+#[derive(Debug, Clone)]
+pub struct CaBundle {
+    roots: Arc<RootCertStore>,
+}
+
+impl CaBundle {
+    /// Loads one or more PEM CA certificates.
+    pub fn from_pem(pem: &[u8]) -> Result<Self, TlsError> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_slice_iter(pem) {
+            roots
+                .add(cert.map_err(TlsError::Pem)?)
+                .map_err(TlsError::Ca)?;
+        }
+        if roots.is_empty() {
+            return Err(TlsError::NoCertificate);
+        }
+        Ok(Self {
+            roots: Arc::new(roots),
+        })
+    }
+
+    pub fn from_pem_file(path: impl AsRef<Path>) -> Result<Self, TlsError> {
+        Self::from_pem(&read(path.as_ref())?)
+    }
+}
+
+/// Whether clients must present a certificate.
+#[derive(Debug, Clone, Default)]
+pub enum ClientAuth {
+    /// Client certificates are not asked for.
+    #[default]
+    None,
+    /// Clients may present a certificate, which must then verify against the
+    /// bundle; clients without one are let through.
+    Optional(CaBundle),
+    /// Clients must present a certificate that verifies against the bundle,
+    /// or the handshake fails.
+    Required(CaBundle),
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, TlsError> {
+    fs::read(path).map_err(|source| TlsError::Read {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Serves HTTPS with a default certificate, plus others picked by the host
+/// name the client asks for through SNI.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    resolver: Arc<Resolver>,
+    client_auth: ClientAuth,
+    server_config: Arc<rustls::ServerConfig>,
+}
+
+impl TlsConfig {
+    /// Uses `default` for clients that send no host name through SNI, or one
+    /// with no certificate of its own.
+    pub fn new(default: Certificate) -> Self {
+        let resolver = Resolver {
+            default: default.key,
+            by_name: HashMap::new(),
+        };
+        Self::build(Arc::new(resolver), ClientAuth::None)
+    }
+
+    /// Uses `certificate` for clients asking for `server_name`, which is
+    /// matched without regard to case.
+    pub fn sni(self, server_name: &str, certificate: Certificate) -> Self {
+        let mut resolver = Resolver::clone(&self.resolver);
+        resolver
+            .by_name
+            .insert(server_name.to_ascii_lowercase(), certificate.key);
+        Self::build(Arc::new(resolver), self.client_auth)
+    }
+
+    /// Asks clients for a certificate. The verified certificate is reported
+    /// in [`TlsInfo::client_certificate`].
+    pub fn client_auth(self, client_auth: ClientAuth) -> Self {
+        Self::build(self.resolver, client_auth)
+    }
+
+    fn build(resolver: Arc<Resolver>, client_auth: ClientAuth) -> Self {
+        let provider = Arc::new(provider());
+        let verifier = match &client_auth {
+            ClientAuth::None => WebPkiClientVerifier::no_client_auth(),
+            ClientAuth::Optional(ca) => {
+                WebPkiClientVerifier::builder_with_provider(Arc::clone(&ca.roots), provider.clone())
+                    .allow_unauthenticated()
+                    .build()
+                    .expect("a CA bundle holds at least one trust anchor")
+            }
+            ClientAuth::Required(ca) => {
+                WebPkiClientVerifier::builder_with_provider(Arc::clone(&ca.roots), provider.clone())
+                    .build()
+                    .expect("a CA bundle holds at least one trust anchor")
+            }
+        };
+        let mut server_config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .expect("the ring provider supports the default protocol versions")
+            .with_client_cert_verifier(verifier)
+            .with_cert_resolver(Arc::clone(&resolver) as Arc<dyn ResolvesServerCert>);
+        server_config.alpn_protocols = vec![ALPN_HTTP_1_1.to_vec()];
+        Self {
+            resolver,
+            client_auth,
+            server_config: Arc::new(server_config),
+        }
+    }
+}
+
+fn provider() -> CryptoProvider {
+    ring::default_provider()
+}
+
+#[derive(Debug, Clone)]
+struct Resolver {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let key = client_hello
+            .server_name()
+            .and_then(|name| self.by_name.get(&name.to_ascii_lowercase()))
+            .unwrap_or(&self.default);
+        Some(Arc::clone(key))
+    }
+}
+
+/// A connection with TLS terminated, read and written as plain text.
+pub(crate) struct TlsStream {
+    inner: StreamOwned<ServerConnection, Stream>,
+}
+
+/// Completes the TLS handshake on `stream` within `timeout`.
+pub(crate) fn accept(
+    config: &TlsConfig,
+    mut stream: Stream,
+    timeout: Option<Duration>,
+) -> io::Result<(TlsStream, TlsInfo)> {
+    let mut connection =
+        ServerConnection::new(Arc::clone(&config.server_config)).map_err(io::Error::other)?;
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    while connection.is_handshaking() {
+        // A zero timeout would mean no timeout at all.
+        let remaining = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => Some(remaining),
+                _ => return Err(io::ErrorKind::TimedOut.into()),
+            },
+            None => None,
+        };
+        stream.set_read_timeout(remaining)?;
+        stream.set_write_timeout(remaining)?;
+        connection.complete_io(&mut stream)?;
+    }
+
+    let info = TlsInfo {
+        protocol_version: connection
+            .protocol_version()
+            .and_then(|version| version.as_str())
+            .unwrap_or("unknown")
+            .to_string(),
+        cipher_suite: connection
+            .negotiated_cipher_suite()
+            .and_then(|suite| suite.suite().as_str())
+            .unwrap_or("unknown")
+            .to_string(),
+        alpn_protocol: connection
+            .alpn_protocol()
+            .map(|protocol| String::from_utf8_lossy(protocol).into_owned()),
+        server_name: connection.server_name().map(str::to_string),
+        client_certificate: connection
+            .peer_certificates()
+            .and_then(|chain| chain.first())
+            .and_then(|cert| ClientCertificate::from_der(cert)),
+    };
+    let inner = StreamOwned::new(connection, stream);
+    Ok((TlsStream { inner }, info))
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl SocketTimeouts for TlsStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.sock.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.sock.set_write_timeout(timeout)
+    }
+}
+
+impl Drop for TlsStream {
+    // Tells the client the connection ended on purpose rather than being
+    // cut off.
+    fn drop(&mut self) {
+        self.inner.conn.send_close_notify();
+        while self.inner.conn.wants_write() {
+            if self.inner.conn.write_tls(&mut self.inner.sock).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn self_signed(name: &str) -> (String, String) {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        (cert.cert.pem(), cert.signing_key.serialize_pem())
+    }
+
+    #[test]
+    fn test_certificate_from_pem() {
+        let (cert, key) = self_signed("localhost");
+        assert!(Certificate::from_pem(cert.as_bytes(), key.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn test_certificate_needs_both_parts() {
+        let (cert, key) = self_signed("localhost");
+        assert!(matches!(
+            Certificate::from_pem(b"", key.as_bytes()),
+            Err(TlsError::NoCertificate)
+        ));
+        assert!(matches!(
+            Certificate::from_pem(cert.as_bytes(), b""),
+            Err(TlsError::NoPrivateKey)
+        ));
+    }
+
+    #[test]
+    fn test_certificate_rejects_another_key() {
+        let (cert, _) = self_signed("localhost");
+        let (_, other_key) = self_signed("localhost");
+        assert!(matches!(
+            Certificate::from_pem(cert.as_bytes(), other_key.as_bytes()),
+            Err(TlsError::Key(_))
+        ));
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let path = std::env::temp_dir().join("http-from-tcp-missing.pem");
+        match Certificate::from_pem_files(&path, &path) {
+            Err(TlsError::Read { path: failed, .. }) => assert_eq!(failed, path),
+            other => panic!("expected a read error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_ca_bundle_needs_a_certificate() {
+        let (cert, _) = self_signed("ca");
+        assert!(CaBundle::from_pem(cert.as_bytes()).is_ok());
+        assert!(matches!(
+            CaBundle::from_pem(b""),
+            Err(TlsError::NoCertificate)
+        ));
+    }
+
+    #[test]
+    fn test_client_certificate_identity() {
+        let mut params = rcgen::CertificateParams::new(vec![
+            "billing.internal".to_string(),
+            "10.0.0.7".to_string(),
+        ])
+        .unwrap();
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "billing");
+        let key = rcgen::KeyPair::generate().unwrap();
+        let der = params.self_signed(&key).unwrap().der().to_vec();
+
+        let cert = ClientCertificate::from_der(&der).unwrap();
+        assert_eq!(cert.subject, "CN=billing");
+        assert_eq!(
+            cert.subject_alt_names,
+            vec![
+                SubjectAltName::Dns("billing.internal".to_string()),
+                SubjectAltName::Ip(IpAddr::from([10, 0, 0, 7])),
+            ]
+        );
+        assert_eq!(cert.fingerprint.len(), 64);
+        assert!(cert.fingerprint.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_eq!(cert.fingerprint, cert.fingerprint.to_lowercase());
+    }
+}
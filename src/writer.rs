@@ -0,0 +1,386 @@
+use std::fmt;
+use std::io::{Error as IoError, Write};
+
+use crate::headers::Headers;
+use crate::response::{StatusCode, write_headers, write_status_line};
+
+/// Where a [`ResponseWriter`] is in the response. Each part can only be
+/// written once the previous one is complete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriterState {
+    StatusLine,
+    Headers,
+    Body,
+    /// The body has ended and trailer fields may still be sent.
+    Trailers,
+    Done,
+}
+
+#[derive(Debug)]
+pub enum WriterError {
+    /// The call is not allowed in the state the writer was in.
+    InvalidState(WriterState),
+    /// A chunked write was attempted but the headers did not declare
+    /// `transfer-encoding: chunked`.
+    NotChunked,
+    /// The client speaks HTTP/1.0, which has no chunked transfer coding.
+    ChunkedUnsupported,
+    /// A trailer field was not announced in the `trailer` header.
+    UndeclaredTrailer(String),
+    Io(IoError),
+}
+
+impl fmt::Display for WriterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriterError::InvalidState(state) => {
+                write!(f, "response part written out of order in state {:?}", state)
+            }
+            WriterError::NotChunked => write!(f, "response is not chunked"),
+            WriterError::ChunkedUnsupported => {
+                write!(f, "chunked transfer coding is not supported by HTTP/1.0")
+            }
+            WriterError::UndeclaredTrailer(name) => {
+                write!(f, "trailer {} was not declared in the trailer header", name)
+            }
+            WriterError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<IoError> for WriterError {
+    fn from(e: IoError) -> Self {
+        WriterError::Io(e)
+    }
+}
+
+/// Writes a response to a connection one part at a time, for responses that
+/// are produced incrementally rather than buffered in a `Response`. Calls
+/// made out of order fail with `WriterError::InvalidState` and write nothing,
+/// so the stream is never left half-formed by a programming error.
+///
+/// When the headers declare `transfer-encoding: chunked` the body is framed
+/// as chunks, ended with [`ResponseWriter::write_chunked_body_done`] and
+/// optionally followed by the trailer fields named in the `trailer` header.
+#[derive(Debug)]
+pub struct ResponseWriter<W: Write> {
+    writer: W,
+    state: WriterState,
+    http_version: String,
+    chunked: bool,
+    declared_trailers: Vec<String>,
+    body_bytes: u64,
+}
+
+impl<W: Write> ResponseWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self::with_http_version(writer, "1.1")
+    }
+
+    /// A writer for a client that sent the given HTTP version, e.g. `"1.0"`.
+    pub fn with_http_version(writer: W, http_version: &str) -> Self {
+        Self {
+            writer,
+            state: WriterState::StatusLine,
+            http_version: http_version.to_string(),
+            chunked: false,
+            declared_trailers: Vec::new(),
+            body_bytes: 0,
+        }
+    }
+
+    pub fn http_version(&self) -> &str {
+        &self.http_version
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        self.chunked
+    }
+
+    pub fn state(&self) -> WriterState {
+        self.state
+    }
+
+    /// How many bytes of body have been written, not counting chunk framing.
+    pub fn body_bytes(&self) -> u64 {
+        self.body_bytes
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    pub fn write_status_line(&mut self, status_code: &StatusCode) -> Result<(), WriterError> {
+        self.expect(WriterState::StatusLine)?;
+        write_status_line(&mut self.writer, status_code)?;
+        self.state = WriterState::Headers;
+        Ok(())
+    }
+
+    pub fn write_headers(&mut self, headers: &Headers) -> Result<(), WriterError> {
+        self.expect(WriterState::Headers)?;
+        let chunked = is_chunked(headers);
+        if chunked && self.http_version == "1.0" {
+            return Err(WriterError::ChunkedUnsupported);
+        }
+        write_headers(&mut self.writer, headers)?;
+        self.chunked = chunked;
+        self.declared_trailers = headers
+            .get("trailer")
+            .map(|names| {
+                names
+                    .split(',')
+                    .map(|name| name.trim().to_lowercase())
+                    .filter(|name| !name.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        self.state = WriterState::Body;
+        Ok(())
+    }
+
+    /// Writes part of the body. May be called any number of times; on a
+    /// chunked response each call is sent as one chunk.
+    pub fn write_body(&mut self, data: &[u8]) -> Result<usize, WriterError> {
+        if self.chunked {
+            return self.write_chunked_body(data);
+        }
+        self.expect(WriterState::Body)?;
+        self.writer.write_all(data)?;
+        self.body_bytes += data.len() as u64;
+        Ok(data.len())
+    }
+
+    /// Writes `chunk` as one chunk. Empty chunks are skipped, since a zero
+    /// size chunk would end the body.
+    pub fn write_chunked_body(&mut self, chunk: &[u8]) -> Result<usize, WriterError> {
+        self.expect(WriterState::Body)?;
+        if !self.chunked {
+            return Err(WriterError::NotChunked);
+        }
+        if chunk.is_empty() {
+            return Ok(0);
+        }
+        write!(self.writer, "{:x}\r\n", chunk.len())?;
+        self.writer.write_all(chunk)?;
+        write!(self.writer, "\r\n")?;
+        self.body_bytes += chunk.len() as u64;
+        Ok(chunk.len())
+    }
+
+    /// Writes the last chunk. Trailers may follow; otherwise call
+    /// [`ResponseWriter::finish`].
+    pub fn write_chunked_body_done(&mut self) -> Result<(), WriterError> {
+        self.expect(WriterState::Body)?;
+        if !self.chunked {
+            return Err(WriterError::NotChunked);
+        }
+        write!(self.writer, "0\r\n")?;
+        self.state = WriterState::Trailers;
+        Ok(())
+    }
+
+    /// Writes the trailer section and ends the response. Every field must
+    /// have been announced in the `trailer` header.
+    pub fn write_trailers(&mut self, trailers: &Headers) -> Result<(), WriterError> {
+        self.expect(WriterState::Trailers)?;
+        if let Some(name) = trailers
+            .map
+            .keys()
+            .find(|name| !self.declared_trailers.contains(name))
+        {
+            return Err(WriterError::UndeclaredTrailer(name.clone()));
+        }
+        write_headers(&mut self.writer, trailers)?;
+        self.writer.flush()?;
+        self.state = WriterState::Done;
+        Ok(())
+    }
+
+    /// Ends the response and flushes the connection. A response without a
+    /// body can be finished straight after its headers, and a chunked body
+    /// is terminated with an empty trailer section.
+    pub fn finish(&mut self) -> Result<(), WriterError> {
+        match self.state {
+            WriterState::Body if self.chunked => {
+                self.write_chunked_body_done()?;
+                self.finish()
+            }
+            WriterState::Trailers => {
+                write!(self.writer, "\r\n")?;
+                self.writer.flush()?;
+                self.state = WriterState::Done;
+                Ok(())
+            }
+            WriterState::Body => {
+                self.writer.flush()?;
+                self.state = WriterState::Done;
+                Ok(())
+            }
+            state => Err(WriterError::InvalidState(state)),
+        }
+    }
+
+    /// Ends a response straight after its headers, even when they describe
+    /// a body, as for an answer to a `HEAD` request.
+    pub fn finish_without_body(&mut self) -> Result<(), WriterError> {
+        self.expect(WriterState::Body)?;
+        self.writer.flush()?;
+        self.state = WriterState::Done;
+        Ok(())
+    }
+
+    fn expect(&self, state: WriterState) -> Result<(), WriterError> {
+        if self.state != state {
+            return Err(WriterError::InvalidState(self.state));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `chunked` is the final transfer coding declared in `headers`.
+pub fn is_chunked(headers: &Headers) -> bool {
+    headers
+        .get("transfer-encoding")
+        .and_then(|codings| codings.rsplit(',').next())
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_writes_parts_in_order() {
+        let mut writer = ResponseWriter::new(Vec::new());
+        let mut headers = Headers::new();
+        headers.set("Content-Length", "5");
+
+        writer.write_status_line(&StatusCode::OK).unwrap();
+        writer.write_headers(&headers).unwrap();
+        writer.write_body(b"he").unwrap();
+        writer.write_body(b"llo").unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(writer.state(), WriterState::Done);
+        assert_eq!(writer.body_bytes(), 5);
+        assert_eq!(
+            writer.into_inner(),
+            b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhello"
+        );
+    }
+
+    #[test]
+    fn test_headers_before_status_line() {
+        let mut writer = ResponseWriter::new(Vec::new());
+        let err = writer.write_headers(&Headers::new()).unwrap_err();
+        assert!(matches!(
+            err,
+            WriterError::InvalidState(WriterState::StatusLine)
+        ));
+        assert!(writer.into_inner().is_empty());
+    }
+
+    #[test]
+    fn test_headers_after_body() {
+        let mut writer = ResponseWriter::new(Vec::new());
+        writer.write_status_line(&StatusCode::OK).unwrap();
+        writer.write_headers(&Headers::new()).unwrap();
+        writer.write_body(b"x").unwrap();
+
+        let err = writer.write_headers(&Headers::new()).unwrap_err();
+        assert!(matches!(err, WriterError::InvalidState(WriterState::Body)));
+        let err = writer.write_status_line(&StatusCode::OK).unwrap_err();
+        assert!(matches!(err, WriterError::InvalidState(WriterState::Body)));
+    }
+
+    #[test]
+    fn test_nothing_after_done() {
+        let mut writer = ResponseWriter::new(Vec::new());
+        writer.write_status_line(&StatusCode::NoContent).unwrap();
+        writer.write_headers(&Headers::new()).unwrap();
+        writer.finish().unwrap();
+
+        let err = writer.write_body(b"late").unwrap_err();
+        assert!(matches!(err, WriterError::InvalidState(WriterState::Done)));
+        assert!(writer.finish().is_err());
+    }
+
+    fn chunked_headers() -> Headers {
+        let mut headers = Headers::new();
+        headers.set("Transfer-Encoding", "chunked");
+        headers.set("Trailer", "X-Content-SHA256, X-Content-Length");
+        headers
+    }
+
+    #[test]
+    fn test_chunked_body_with_trailers() {
+        let mut writer = ResponseWriter::new(Vec::new());
+        writer.write_status_line(&StatusCode::OK).unwrap();
+        writer.write_headers(&chunked_headers()).unwrap();
+
+        let mut length = 0;
+        for chunk in [&b"hello, "[..], b"", b"world"] {
+            length += writer.write_chunked_body(chunk).unwrap();
+        }
+        writer.write_chunked_body_done().unwrap();
+
+        let mut trailers = Headers::new();
+        trailers.set("X-Content-Length", &length.to_string());
+        writer.write_trailers(&trailers).unwrap();
+        assert_eq!(writer.state(), WriterState::Done);
+        assert_eq!(writer.body_bytes(), 12);
+
+        let out = String::from_utf8(writer.into_inner()).unwrap();
+        let body = out.split_once("\r\n\r\n").unwrap().1;
+        assert_eq!(
+            body,
+            "7\r\nhello, \r\n5\r\nworld\r\n0\r\nx-content-length: 12\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_finish_ends_chunked_body() {
+        let mut headers = Headers::new();
+        headers.set("Transfer-Encoding", "chunked");
+        let mut writer = ResponseWriter::new(Vec::new());
+        writer.write_status_line(&StatusCode::OK).unwrap();
+        writer.write_headers(&headers).unwrap();
+        writer.write_body(b"abc").unwrap();
+        writer.finish().unwrap();
+
+        let out = String::from_utf8(writer.into_inner()).unwrap();
+        assert!(out.ends_with("\r\n\r\n3\r\nabc\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_undeclared_trailer() {
+        let mut writer = ResponseWriter::new(Vec::new());
+        writer.write_status_line(&StatusCode::OK).unwrap();
+        writer.write_headers(&chunked_headers()).unwrap();
+        writer.write_chunked_body_done().unwrap();
+
+        let mut trailers = Headers::new();
+        trailers.set("X-Other", "1");
+        let err = writer.write_trailers(&trailers).unwrap_err();
+        assert!(matches!(err, WriterError::UndeclaredTrailer(name) if name == "x-other"));
+    }
+
+    #[test]
+    fn test_chunked_requires_chunked_headers() {
+        let mut writer = ResponseWriter::new(Vec::new());
+        writer.write_status_line(&StatusCode::OK).unwrap();
+        writer.write_headers(&Headers::new()).unwrap();
+        let err = writer.write_chunked_body(b"x").unwrap_err();
+        assert!(matches!(err, WriterError::NotChunked));
+    }
+
+    #[test]
+    fn test_refuses_chunked_for_http_1_0() {
+        let mut writer = ResponseWriter::with_http_version(Vec::new(), "1.0");
+        writer.write_status_line(&StatusCode::OK).unwrap();
+        let err = writer.write_headers(&chunked_headers()).unwrap_err();
+        assert!(matches!(err, WriterError::ChunkedUnsupported));
+        assert_eq!(writer.state(), WriterState::Headers);
+    }
+}
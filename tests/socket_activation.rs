@@ -0,0 +1,128 @@
+#![cfg(unix)]
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+/// Starts the server binary the way a service manager would, with
+/// `listener` as descriptor 3 and the activation variables pointing at it.
+fn spawn_with_listener(listener: &TcpListener) -> (Child, BufReader<ChildStdout>) {
+    spawn_with_listeners(&[listener])
+}
+
+/// Like [`spawn_with_listener`], passing `listeners` as descriptors 3
+/// onwards.
+fn spawn_with_listeners(listeners: &[&TcpListener]) -> (Child, BufReader<ChildStdout>) {
+    let fds: Vec<RawFd> = listeners.iter().map(|l| l.as_raw_fd()).collect();
+    let names = vec!["http"; fds.len()].join(":");
+    let mut command = Command::new("sh");
+    // LISTEN_PID has to name the server itself, which `exec` makes the
+    // shell's own pid.
+    command
+        .arg("-c")
+        .arg("LISTEN_PID=$$ exec \"$0\"")
+        .arg(env!("CARGO_BIN_EXE_http-from-tcp"))
+        .env("LISTEN_FDS", fds.len().to_string())
+        .env("LISTEN_FDNAMES", names)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // SAFETY: only async-signal-safe calls are made between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            // Moved out of the way first, in case one of them already sits
+            // where another belongs.
+            const SPARE: RawFd = 100;
+            for (i, fd) in fds.iter().enumerate() {
+                if libc::fcntl(*fd, libc::F_DUPFD, SPARE + i as RawFd) != SPARE + i as RawFd {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            for i in 0..fds.len() as RawFd {
+                if libc::dup2(SPARE + i, 3 + i) < 0 || libc::close(SPARE + i) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    (child, stdout)
+}
+
+fn read_line(stdout: &mut BufReader<ChildStdout>) -> String {
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    line
+}
+
+/// Interrupts the server and returns the rest of what it printed.
+fn stop(mut child: Child, mut stdout: BufReader<ChildStdout>) -> String {
+    // SAFETY: `kill` only sends a signal to the child process.
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    let mut output = String::new();
+    stdout.read_to_string(&mut output).unwrap();
+    assert!(child.wait().unwrap().success());
+    output
+}
+
+fn get(addr: SocketAddr) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn serves_on_activated_listener() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (child, mut stdout) = spawn_with_listener(&listener);
+    assert_eq!(read_line(&mut stdout), format!("Listening on {}\n", addr));
+    // Only the server's copy of the socket is left.
+    drop(listener);
+
+    assert!(get(addr).ends_with("\r\n\r\nAll good\n"));
+    assert!(stop(child, stdout).contains("Server stopped"));
+}
+
+#[test]
+fn restart_keeps_the_listener_open() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (old, mut old_stdout) = spawn_with_listener(&listener);
+    read_line(&mut old_stdout);
+    assert!(get(addr).ends_with("All good\n"));
+
+    // The replacement starts on the same socket before the old server stops,
+    // so no connection is refused in between.
+    let (new, mut new_stdout) = spawn_with_listener(&listener);
+    read_line(&mut new_stdout);
+    stop(old, old_stdout);
+    for _ in 0..3 {
+        assert!(get(addr).ends_with("All good\n"));
+    }
+    stop(new, new_stdout);
+}
+
+#[test]
+fn refuses_more_than_one_listener() {
+    let first = TcpListener::bind("127.0.0.1:0").unwrap();
+    let second = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (mut child, _stdout) = spawn_with_listeners(&[&first, &second]);
+
+    let mut stderr = String::new();
+    child
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    assert!(!child.wait().unwrap().success());
+    assert!(stderr.contains("Expected one inherited listener, got 2"));
+}
@@ -0,0 +1,185 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use http_from_tcp::body::Body;
+use http_from_tcp::request::Request;
+use http_from_tcp::response::Response;
+use http_from_tcp::server::{Server, ServerConfig};
+
+fn start(config: ServerConfig) -> (Server, SocketAddr) {
+    let server = Server::bind_with_config("127.0.0.1:0", config, |request: &Request| {
+        Response::builder()
+            .body(format!("got {} bytes", request.body.len()))
+            .build()
+    })
+    .unwrap();
+    let addr = server.local_addr().unwrap();
+    (server, addr)
+}
+
+fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+}
+
+/// Sends `data` one byte at a time, `delay` apart, stopping early if the
+/// server closes the connection.
+fn trickle(stream: &mut TcpStream, data: &[u8], delay: Duration) {
+    for byte in data {
+        if stream.write_all(&[*byte]).is_err() {
+            return;
+        }
+        thread::sleep(delay);
+    }
+}
+
+fn read_response(stream: &mut TcpStream) -> String {
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+#[test]
+fn slow_headers_get_request_timeout() {
+    let config = ServerConfig::new().header_timeout(Some(Duration::from_millis(300)));
+    let (server, addr) = start(config);
+
+    let mut client = connect(addr);
+    let started = Instant::now();
+    trickle(
+        &mut client,
+        b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Slow: aaaaaaaaaaaaaaaa\r\n\r\n",
+        Duration::from_millis(50),
+    );
+    let response = read_response(&mut client);
+
+    assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    assert!(response.contains("connection: close\r\n"));
+    assert!(started.elapsed() < Duration::from_secs(3));
+    server.close();
+}
+
+#[test]
+fn slow_body_gets_request_timeout() {
+    let config = ServerConfig::new()
+        .body_timeout(Some(Duration::from_millis(200)))
+        .min_body_rate(Some(100));
+    let (server, addr) = start(config);
+
+    // 20 bytes at 100 bytes/s gets 200ms on top of the base 200ms; sending
+    // one byte every 50ms needs a full second.
+    let mut client = connect(addr);
+    client
+        .write_all(b"POST / HTTP/1.1\r\nContent-Length: 20\r\n\r\n")
+        .unwrap();
+    trickle(&mut client, &[b'x'; 20], Duration::from_millis(50));
+    let response = read_response(&mut client);
+
+    assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    server.close();
+}
+
+#[test]
+fn declared_length_does_not_extend_body_timeout() {
+    let config = ServerConfig::new()
+        .body_timeout(Some(Duration::from_millis(200)))
+        .min_body_rate(Some(1));
+    let (server, addr) = start(config);
+
+    // The client claims an enormous body but sends it far below the rate
+    // needed to finish; only what actually arrives earns more time.
+    let mut client = connect(addr);
+    client
+        .write_all(b"POST / HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\n")
+        .unwrap();
+    let started = Instant::now();
+    trickle(&mut client, b"x", Duration::from_millis(10));
+    let response = read_response(&mut client);
+
+    assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    assert!(started.elapsed() < Duration::from_secs(3));
+    server.close();
+}
+
+#[test]
+fn body_at_the_minimum_rate_is_accepted() {
+    let config = ServerConfig::new()
+        .body_timeout(Some(Duration::from_millis(200)))
+        .min_body_rate(Some(100));
+    let (server, addr) = start(config);
+
+    let mut client = connect(addr);
+    client
+        .write_all(b"POST / HTTP/1.1\r\nContent-Length: 20\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    trickle(&mut client, &[b'x'; 20], Duration::from_millis(5));
+    let response = read_response(&mut client);
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("got 20 bytes"));
+    server.close();
+}
+
+#[test]
+fn idle_keep_alive_connection_is_closed_quietly() {
+    let config = ServerConfig::new().idle_timeout(Some(Duration::from_millis(200)));
+    let (server, addr) = start(config);
+
+    let mut client = connect(addr);
+    client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    let mut buffer = [0u8; 1024];
+    let mut response = String::new();
+    while !response.ends_with("got 0 bytes") {
+        let n = client.read(&mut buffer).unwrap();
+        assert!(n > 0);
+        response.push_str(&String::from_utf8_lossy(&buffer[..n]));
+    }
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+    // Nothing more is sent; the server hangs up without a 408.
+    let started = Instant::now();
+    let rest = read_response(&mut client);
+    assert_eq!(rest, "");
+    assert!(started.elapsed() < Duration::from_secs(3));
+    server.close();
+}
+
+#[test]
+fn client_that_stops_reading_hits_write_timeout() {
+    const BODY_LEN: u64 = 64 * 1024 * 1024;
+    let config = ServerConfig::new().write_timeout(Some(Duration::from_millis(100)));
+    let server = Server::bind_with_config("127.0.0.1:0", config, |_: &Request| {
+        Response::builder()
+            .body(Body::from_reader(
+                io::repeat(b'x').take(BODY_LEN),
+                Some(BODY_LEN),
+            ))
+            .build()
+    })
+    .unwrap();
+
+    let mut client = connect(server.local_addr().unwrap());
+    client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    // Long enough for the socket buffers to fill and the server's write to
+    // block past its timeout.
+    thread::sleep(Duration::from_secs(3));
+
+    // The server gave up partway, so the connection ends short of the body.
+    let mut received = 0u64;
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        match client.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => received += n as u64,
+        }
+    }
+    assert!(received < BODY_LEN);
+
+    let report = server.close();
+    assert_eq!(report.aborted, 0);
+}
@@ -0,0 +1,275 @@
+#![cfg(feature = "tls")]
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use http_from_tcp::net::ConnectionInfo;
+use http_from_tcp::request::Request;
+use http_from_tcp::response::Response;
+use http_from_tcp::server::{Server, ServerConfig};
+use http_from_tcp::tls::{CaBundle, Certificate, ClientAuth, SubjectAltName, TlsConfig};
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    Issuer, KeyPair,
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+struct SelfSigned {
+    der: CertificateDer<'static>,
+    certificate: Certificate,
+}
+
+/// Generates a self-signed certificate for `name` and loads it from PEM
+/// files, as a deployment would.
+fn self_signed(name: &str) -> SelfSigned {
+    let generated = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+    let dir = std::env::temp_dir();
+    let file = |suffix: &str| -> PathBuf {
+        dir.join(format!(
+            "http-from-tcp-{}-{}.{}",
+            std::process::id(),
+            name,
+            suffix
+        ))
+    };
+    let (cert_path, key_path) = (file("crt"), file("key"));
+    fs::write(&cert_path, generated.cert.pem()).unwrap();
+    fs::write(&key_path, generated.signing_key.serialize_pem()).unwrap();
+
+    let certificate = Certificate::from_pem_files(&cert_path, &key_path).unwrap();
+    fs::remove_file(cert_path).unwrap();
+    fs::remove_file(key_path).unwrap();
+    SelfSigned {
+        der: generated.cert.der().clone(),
+        certificate,
+    }
+}
+
+/// Answers with the TLS details the server negotiated.
+fn describe_tls(request: &Request) -> Response {
+    let info = request.extensions.get::<ConnectionInfo>().unwrap();
+    let tls = info.tls.as_ref().unwrap();
+    Response::builder()
+        .body(format!(
+            "{} {:?} {:?}",
+            tls.protocol_version, tls.alpn_protocol, tls.server_name
+        ))
+        .build()
+}
+
+fn start(tls: TlsConfig) -> (Server, SocketAddr) {
+    let config = ServerConfig::new().tls(tls);
+    let server = Server::bind_with_config("127.0.0.1:0", config, describe_tls).unwrap();
+    let addr = server.local_addr().unwrap();
+    (server, addr)
+}
+
+/// A certificate authority for issuing client certificates.
+struct Ca {
+    pem: String,
+    issuer: Issuer<'static, KeyPair>,
+}
+
+fn new_ca(name: &str) -> Ca {
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, name);
+    let key = KeyPair::generate().unwrap();
+    let cert = params.self_signed(&key).unwrap();
+    Ca {
+        pem: cert.pem(),
+        issuer: Issuer::new(params, key),
+    }
+}
+
+/// A client certificate chain and key.
+type Identity = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+fn client_identity(ca: &Ca, common_name: &str, dns_name: &str) -> Identity {
+    let mut params = CertificateParams::new(vec![dns_name.to_string()]).unwrap();
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, common_name);
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let key = KeyPair::generate().unwrap();
+    let cert = params.signed_by(&key, &ca.issuer).unwrap();
+    let key = PrivateKeyDer::try_from(key.serialize_der()).unwrap();
+    (vec![cert.der().clone()], key)
+}
+
+/// Makes one HTTPS request, trusting only `trusted` and asking for
+/// `server_name`.
+fn https_get(
+    addr: SocketAddr,
+    server_name: &str,
+    trusted: &SelfSigned,
+) -> Result<String, std::io::Error> {
+    https_get_as(addr, server_name, trusted, None)
+}
+
+/// Like [`https_get`], authenticating with `identity` if given.
+fn https_get_as(
+    addr: SocketAddr,
+    server_name: &str,
+    trusted: &SelfSigned,
+    identity: Option<Identity>,
+) -> Result<String, std::io::Error> {
+    let mut roots = RootCertStore::empty();
+    roots.add(trusted.der.clone()).unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+    let mut config = match identity {
+        Some((chain, key)) => builder.with_client_auth_cert(chain, key).unwrap(),
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    let name = ServerName::try_from(server_name.to_string()).unwrap();
+    let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+    let socket = TcpStream::connect(addr)?;
+    socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut stream = StreamOwned::new(connection, socket);
+    stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+#[test]
+fn serves_https() {
+    let localhost = self_signed("localhost");
+    let (server, addr) = start(TlsConfig::new(localhost.certificate.clone()));
+
+    let response = https_get(addr, "localhost", &localhost).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("\r\n\r\nTLSv1_3 Some(\"http/1.1\") Some(\"localhost\")"));
+    server.close();
+}
+
+#[test]
+fn picks_certificate_by_server_name() {
+    let localhost = self_signed("localhost");
+    let other = self_signed("other.test");
+    let tls =
+        TlsConfig::new(localhost.certificate.clone()).sni("Other.Test", other.certificate.clone());
+    let (server, addr) = start(tls);
+
+    let response = https_get(addr, "other.test", &other).unwrap();
+    assert!(response.ends_with("Some(\"other.test\")"));
+    // Any other name gets the default certificate, which the client
+    // trusting only `other` rejects.
+    assert!(https_get(addr, "localhost", &other).is_err());
+    assert!(https_get(addr, "localhost", &localhost).is_ok());
+    server.close();
+}
+
+#[test]
+fn plain_http_gets_no_response() {
+    let localhost = self_signed("localhost");
+    let (server, addr) = start(TlsConfig::new(localhost.certificate.clone()));
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    client
+        .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = Vec::new();
+    let _ = client.read_to_end(&mut response);
+    assert!(!response.starts_with(b"HTTP/1.1"));
+
+    // The server is still serving after the failed handshake.
+    assert!(https_get(addr, "localhost", &localhost).is_ok());
+    server.close();
+}
+
+/// Answers with the verified client identity, or `anonymous`.
+fn describe_client(request: &Request) -> Response {
+    let info = request.extensions.get::<ConnectionInfo>().unwrap();
+    let body = match &info.tls.as_ref().unwrap().client_certificate {
+        Some(cert) => format!(
+            "{} {:?} {}",
+            cert.subject, cert.subject_alt_names, cert.fingerprint
+        ),
+        None => "anonymous".to_string(),
+    };
+    Response::builder().body(body).build()
+}
+
+fn start_mtls(server_cert: &SelfSigned, client_auth: ClientAuth) -> (Server, SocketAddr) {
+    let tls = TlsConfig::new(server_cert.certificate.clone()).client_auth(client_auth);
+    let config = ServerConfig::new().tls(tls);
+    let server = Server::bind_with_config("127.0.0.1:0", config, describe_client).unwrap();
+    let addr = server.local_addr().unwrap();
+    (server, addr)
+}
+
+#[test]
+fn required_client_auth_exposes_identity() {
+    let localhost = self_signed("localhost");
+    let ca = new_ca("Internal CA");
+    let bundle = CaBundle::from_pem(ca.pem.as_bytes()).unwrap();
+    let (server, addr) = start_mtls(&localhost, ClientAuth::Required(bundle));
+
+    let identity = client_identity(&ca, "billing", "billing.internal");
+    let digest = ring::digest::digest(&ring::digest::SHA256, &identity.0[0]);
+    let fingerprint: String = digest
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let response = https_get_as(addr, "localhost", &localhost, Some(identity)).unwrap();
+    let expected = format!(
+        "\r\n\r\nCN=billing {:?} {}",
+        vec![SubjectAltName::Dns("billing.internal".to_string())],
+        fingerprint
+    );
+    assert!(response.ends_with(&expected), "{}", response);
+
+    // Without a certificate, or with one from another CA, the handshake
+    // fails and no request is served.
+    assert!(https_get(addr, "localhost", &localhost).is_err());
+    let stranger = client_identity(&new_ca("Other CA"), "billing", "billing.internal");
+    assert!(https_get_as(addr, "localhost", &localhost, Some(stranger)).is_err());
+    server.close();
+}
+
+#[test]
+fn optional_client_auth_lets_anonymous_clients_in() {
+    let localhost = self_signed("localhost");
+    let ca = new_ca("Internal CA");
+    let bundle = CaBundle::from_pem(ca.pem.as_bytes()).unwrap();
+    let (server, addr) = start_mtls(&localhost, ClientAuth::Optional(bundle));
+
+    let response = https_get(addr, "localhost", &localhost).unwrap();
+    assert!(response.ends_with("\r\n\r\nanonymous"));
+    let identity = client_identity(&ca, "reports", "reports.internal");
+    let response = https_get_as(addr, "localhost", &localhost, Some(identity)).unwrap();
+    assert!(response.contains("\r\n\r\nCN=reports "));
+
+    // A certificate that is presented still has to verify.
+    let stranger = client_identity(&new_ca("Other CA"), "reports", "reports.internal");
+    assert!(https_get_as(addr, "localhost", &localhost, Some(stranger)).is_err());
+    server.close();
+}
+
+#[test]
+fn no_client_auth_ignores_client_certificates() {
+    let localhost = self_signed("localhost");
+    let (server, addr) = start_mtls(&localhost, ClientAuth::None);
+
+    let response = https_get(addr, "localhost", &localhost).unwrap();
+    assert!(response.ends_with("\r\n\r\nanonymous"));
+    server.close();
+}